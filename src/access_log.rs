@@ -13,7 +13,10 @@ use std::{
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncWriteExt, BufWriter},
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
 };
 use tracing::info;
 
@@ -72,13 +75,20 @@ impl Formater for ScriptFormater {
     }
 }
 
+#[derive(Debug)]
+enum Message {
+    Entry(Arc<ContextProps>),
+    Reopen,
+    Flush(oneshot::Sender<()>),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AccessLog {
     path: PathBuf,
     #[serde(with = "serde_yaml::with::singleton_map_recursive")]
     format: Format,
     #[serde(skip)]
-    tx: Option<Sender<Option<Arc<ContextProps>>>>,
+    tx: Option<Sender<Message>>,
 }
 
 impl AccessLog {
@@ -100,7 +110,7 @@ impl AccessLog {
         self.tx
             .as_ref()
             .unwrap()
-            .send(None)
+            .send(Message::Reopen)
             .await
             .context("enqueue log")
     }
@@ -109,10 +119,22 @@ impl AccessLog {
         self.tx
             .as_ref()
             .unwrap()
-            .send(Some(e))
+            .send(Message::Entry(e))
             .await
             .context("enqueue log")
     }
+
+    /// Waits until all entries enqueued so far are written to file.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .as_ref()
+            .unwrap()
+            .send(Message::Flush(tx))
+            .await
+            .context("enqueue log")?;
+        rx.await.context("flush log")
+    }
 }
 
 async fn log_open(path: &Path) -> Result<File, Error> {
@@ -126,40 +148,47 @@ async fn log_open(path: &Path) -> Result<File, Error> {
 
 async fn log_thread(
     format: Box<dyn Formater>,
-    mut rx: Receiver<Option<Arc<ContextProps>>>,
+    mut rx: Receiver<Message>,
     path: PathBuf,
 ) -> Result<(), Error> {
     let mut stream = BufWriter::new(log_open(&path).await?);
     loop {
         let e = rx.recv().await.ok_or_else(|| err_msg("dequeue"))?;
-        if let Some(e) = e {
-            let mut line = format.to_string(e).context("deserializer error")?;
-            line += "\r\n";
-            stream
-                .write(line.as_bytes())
-                .await
-                .context("log write error")?;
-        } else {
-            info!("log rotate");
-            stream.flush().await.context("flush")?;
-            stream.shutdown().await.context("shutdown")?;
-            stream = BufWriter::new(log_open(&path).await?);
+        match e {
+            Message::Entry(e) => {
+                let mut line = format.to_string(e).context("deserializer error")?;
+                line += "\r\n";
+                stream
+                    .write(line.as_bytes())
+                    .await
+                    .context("log write error")?;
+            }
+            Message::Reopen => {
+                info!("log rotate");
+                stream.flush().await.context("flush")?;
+                stream.shutdown().await.context("shutdown")?;
+                stream = BufWriter::new(log_open(&path).await?);
+            }
+            Message::Flush(done) => {
+                stream.flush().await.context("flush")?;
+                done.send(()).ok();
+            }
         }
     }
 }
 
 #[cfg(target_os = "windows")]
-async fn signal_watch(_tx: Sender<Option<Arc<ContextProps>>>) {}
+async fn signal_watch(_tx: Sender<Message>) {}
 
 #[cfg(not(target_os = "windows"))]
-async fn signal_watch(tx: Sender<Option<Arc<ContextProps>>>) {
+async fn signal_watch(tx: Sender<Message>) {
     use tracing::error;
 
     let mut stream = signal(SignalKind::user_defined1()).unwrap();
     loop {
         let e = stream.recv().await;
         if e.is_some() {
-            tx.send(None).await.unwrap();
+            tx.send(Message::Reopen).await.unwrap();
         } else {
             error!("signal watch ends");
             return;
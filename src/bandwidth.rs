@@ -0,0 +1,169 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use easy_error::{ensure, Error};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Token bucket rate limits, each of them is shared by all connections
+/// of the listener, connector or user it belongs to.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BandwidthLimits {
+    #[serde(default)]
+    listeners: HashMap<String, Bandwidth>,
+    #[serde(default)]
+    connectors: HashMap<String, Bandwidth>,
+    // authenticated users of http and socks listeners
+    #[serde(default)]
+    users: HashMap<String, Bandwidth>,
+    #[serde(skip)]
+    buckets: HashMap<(Kind, String), Buckets>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Bandwidth {
+    // bytes per second, upload and download are limited separately
+    rate: u64,
+    // bytes allowed in a burst, default to one second worth of rate
+    burst: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Listener,
+    Connector,
+    User,
+}
+
+// (upload, download)
+type Buckets = (Arc<TokenBucket>, Arc<TokenBucket>);
+
+impl BandwidthLimits {
+    pub fn init(&mut self) -> Result<(), Error> {
+        let all = [
+            (Kind::Listener, &self.listeners),
+            (Kind::Connector, &self.connectors),
+            (Kind::User, &self.users),
+        ];
+        for (kind, limits) in all {
+            for (name, bw) in limits {
+                ensure!(bw.rate > 0, "bandwidth of {} must greater than zero", name);
+                let burst = bw.burst.unwrap_or(bw.rate);
+                ensure!(burst > 0, "burst of {} must greater than zero", name);
+                let bucket = || Arc::new(TokenBucket::new(bw.rate, burst));
+                self.buckets
+                    .insert((kind, name.to_owned()), (bucket(), bucket()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Limiters of client to server and server to client directions.
+    pub fn limiters(&self, listener: &str, connector: &str, user: &str) -> (Limiter, Limiter) {
+        let mut up = Limiter::default();
+        let mut down = Limiter::default();
+        let keys = [
+            (Kind::Listener, listener),
+            (Kind::Connector, connector),
+            (Kind::User, user),
+        ];
+        for key in keys {
+            if let Some((u, d)) = self.buckets.get(&(key.0, key.1.to_owned())) {
+                up.0.push(u.clone());
+                down.0.push(d.clone());
+            }
+        }
+        (up, down)
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    // available tokens and time of last refill, tokens go negative
+    // when more than available are taken, making later takers wait longer
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: std::sync::Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `n` tokens, returns how long to wait before using them.
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.rate;
+        let tokens = (state.0 + refill).min(self.burst) - n as f64;
+        *state = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+/// Buckets applied to one direction of a connection.
+#[derive(Debug, Default)]
+pub struct Limiter(Vec<Arc<TokenBucket>>);
+
+impl Limiter {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Largest chunk worth reading at once, so a single read never
+    /// exceeds burst of any bucket.
+    pub fn max_chunk(&self, size: usize) -> usize {
+        self.0
+            .iter()
+            .fold(size, |a, b| a.min(b.burst as usize))
+            .max(1)
+    }
+
+    /// Waits until `n` bytes are allowed by all buckets.
+    pub async fn consume(&self, n: usize) {
+        let wait = self.0.iter().map(|b| b.take(n)).max();
+        if let Some(wait) = wait.filter(|x| !x.is_zero()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limiters() {
+        let mut limits: BandwidthLimits = serde_yaml::from_str(
+            "{listeners: {http: {rate: 1000}}, users: {alice: {rate: 100, burst: 50}}}",
+        )
+        .unwrap();
+        limits.init().unwrap();
+        let (up, down) = limits.limiters("http", "direct", "");
+        assert_eq!(up.0.len(), 1);
+        assert_eq!(up.max_chunk(65536), 1000);
+        // burst is available immediately, the rest is paced at rate
+        let start = Instant::now();
+        down.consume(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        down.consume(500).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        // the upload bucket is not affected
+        up.consume(1000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        let (up, _) = limits.limiters("socks", "direct", "alice");
+        assert_eq!(up.max_chunk(65536), 50);
+        assert!(limits.limiters("socks", "direct", "bob").0.is_empty());
+    }
+}
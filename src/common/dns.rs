@@ -4,6 +4,10 @@ use std::{
 };
 
 use easy_error::{err_msg, Error, ResultExt};
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
 use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
 use trust_dns_resolver::{
@@ -33,24 +37,19 @@ impl Default for DnsConfig {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
 pub enum AddressFamily {
     V4Only,
     V6Only,
     V4First,
+    #[default]
     V6First,
 }
 
-impl Default for AddressFamily {
-    fn default() -> Self {
-        AddressFamily::V6First
-    }
-}
-
 impl DnsConfig {
     pub fn init(&mut self) -> Result<(), Error> {
-        let config = Self::parse_servers(&self.servers)?;
-        self.resolver = Some(Arc::new(AsyncResolver::tokio(config.0, config.1).unwrap()));
+        let (config, opts) = Self::parse_servers(&self.servers)?;
+        self.resolver = Some(Arc::new(AsyncResolver::tokio(config, opts).unwrap()));
         Ok(())
     }
 
@@ -127,4 +126,63 @@ impl DnsConfig {
         };
         Ok(SocketAddr::new(addr, port))
     }
+
+    /// Queries every address family allowed at once and yields the answers
+    /// as they arrive, so connecting can start before the slower query
+    /// finishes, as suggested by RFC 8305.
+    pub fn lookup_host_stream<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxStream<'a, Result<Resolved, Error>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let addrs = vec![SocketAddr::new(ip, port)];
+            return stream::iter([Ok(Resolved {
+                preferred: true,
+                addrs,
+            })])
+            .boxed();
+        }
+        let resolver = self.resolver.as_ref().unwrap();
+        let v4 = move |preferred| {
+            async move {
+                let addrs = resolver
+                    .ipv4_lookup(host)
+                    .await
+                    .context("ipv4_lookup")?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(IpAddr::V4(ip), port))
+                    .collect();
+                Ok(Resolved { preferred, addrs })
+            }
+            .into_stream()
+        };
+        let v6 = move |preferred| {
+            async move {
+                let addrs = resolver
+                    .ipv6_lookup(host)
+                    .await
+                    .context("ipv6_lookup")?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(IpAddr::V6(ip), port))
+                    .collect();
+                Ok(Resolved { preferred, addrs })
+            }
+            .into_stream()
+        };
+        match self.family {
+            AddressFamily::V4Only => v4(true).boxed(),
+            AddressFamily::V6Only => v6(true).boxed(),
+            AddressFamily::V4First => stream::select(v4(true), v6(false)).boxed(),
+            AddressFamily::V6First => stream::select(v6(true), v4(false)).boxed(),
+        }
+    }
+}
+
+/// Answer of one query of [`DnsConfig::lookup_host_stream`].
+#[derive(Debug)]
+pub struct Resolved {
+    /// Addresses are of the preferred family.
+    pub preferred: bool,
+    pub addrs: Vec<SocketAddr>,
 }
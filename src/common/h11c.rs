@@ -1,16 +1,28 @@
 use async_trait::async_trait;
-use easy_error::{bail, Error, ResultExt};
+use easy_error::{bail, err_msg, Error, ResultExt};
 use futures::Future;
 use std::{
     net::SocketAddr,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex as StdMutex,
+    },
 };
-use tokio::sync::mpsc::Sender;
-use tracing::{trace, warn};
+use tokio::sync::{mpsc::Sender, oneshot};
+use tracing::{debug, trace, warn};
 
 use crate::{
-    common::http::{HttpRequest, HttpResponse},
-    context::{Context, ContextCallback, ContextRef, ContextRefOps, Feature, IOBufStream},
+    common::{
+        auth::AuthData,
+        http::{
+            basic_auth, copy_body, parse_basic_auth, strip_hop_by_hop_headers, HttpBody,
+            HttpRequest, HttpResponse,
+        },
+    },
+    context::{
+        make_buffered_stream, Context, ContextCallback, ContextRef, ContextRefOps, Feature,
+        IOBufStream,
+    },
 };
 
 use super::frames::{frames_from_stream, FrameIO};
@@ -20,6 +32,7 @@ pub async fn h11c_connect<T1, T2>(
     ctx: ContextRef,
     local: SocketAddr,
     remote: SocketAddr,
+    auth: Option<(String, String)>,
     frame_channel: &str,
     frame_fn: T1,
 ) -> Result<(), Error>
@@ -30,16 +43,19 @@ where
     tracing::trace!("h11c_connect: channel={}", frame_channel);
     let target = ctx.read().await.target();
     let feature = ctx.read().await.feature();
+    let proxy_auth = auth
+        .as_ref()
+        .map(|(user, pass)| basic_auth(user, pass))
+        .unwrap_or_default();
     match feature {
         Feature::TcpForward => {
             HttpRequest::new("CONNECT", &target)
                 .with_header("Host", &target)
+                .with_header("Proxy-Authorization", &proxy_auth)
                 .write_to(&mut server)
                 .await?;
             let resp = HttpResponse::read_from(&mut server).await?;
-            if resp.code != 200 {
-                bail!("upstream server failure: {:?}", resp);
-            }
+            check_connect_response(&resp, auth.is_some())?;
             ctx.write()
                 .await
                 .set_server_stream(server)
@@ -50,7 +66,8 @@ where
             let mut request = HttpRequest::new("CONNECT", &target)
                 .with_header("Host", &target)
                 .with_header("Proxy-Protocol", "udp")
-                .with_header("Proxy-Channel", frame_channel);
+                .with_header("Proxy-Channel", frame_channel)
+                .with_header("Proxy-Authorization", &proxy_auth);
             if feature == Feature::UdpBind {
                 let bind_src = ctx
                     .read()
@@ -64,9 +81,7 @@ where
             request.write_to(&mut server).await?;
             let resp = HttpResponse::read_from(&mut server).await?;
             tracing::trace!("response: {:?}", resp);
-            if resp.code != 200 {
-                bail!("upstream server failure: {:?}", resp);
-            }
+            check_connect_response(&resp, auth.is_some())?;
             let session_id = resp.header("Session-Id", "0").parse().unwrap();
             ctx.write()
                 .await
@@ -83,12 +98,29 @@ where
     Ok(())
 }
 
+fn check_connect_response(resp: &HttpResponse, has_auth: bool) -> Result<(), Error> {
+    match resp.code {
+        200 => Ok(()),
+        407 if has_auth => bail!(
+            "upstream proxy rejected credentials: {} {}",
+            resp.code,
+            resp.status
+        ),
+        407 => bail!(
+            "upstream proxy requires authentication: {}",
+            resp.header("Proxy-Authenticate", "")
+        ),
+        _ => bail!("upstream server failure: {:?}", resp),
+    }
+}
+
 static SESSION_ID: AtomicU32 = AtomicU32::new(0);
 // HTTP 1.1 CONNECT protocol handlers
 // used by http and quic listeners and connectors
 pub async fn h11c_handshake<FrameFn, T2>(
     ctx: ContextRef,
     queue: Sender<ContextRef>,
+    auth: &AuthData,
     create_frames: FrameFn,
 ) -> Result<(), Error>
 where
@@ -99,6 +131,18 @@ where
     let socket = ctx_lock.borrow_client_stream().unwrap();
     let request = HttpRequest::read_from(socket).await?;
     tracing::trace!("request={:?}", request);
+    if request.absolute_uri().is_some() {
+        drop(ctx_lock);
+        return http_forward(ctx, queue, auth, request).await;
+    }
+    if let Some(user) = authenticate(auth, &request, socket).await? {
+        ctx_lock.set_extra("user", user);
+    } else {
+        drop(ctx_lock);
+        ctx.on_error(err_msg("not authenticated")).await;
+        return Ok(());
+    }
+    let socket = ctx_lock.borrow_client_stream().unwrap();
     if request.method.eq_ignore_ascii_case("CONNECT") {
         let protocol = request.header("Proxy-Protocol", "tcp");
         // let host = request.header("Host", "0.0.0.0:0");
@@ -149,6 +193,233 @@ where
     Ok(())
 }
 
+// size of the pipe between a forwarded request and its context
+const FORWARD_PIPE_SIZE: usize = 65536;
+
+// Plain HTTP proxy: each request on the client connection gets its own context,
+// whose client stream is a pipe fed with the rewritten request, so requests can
+// be routed to different targets independently.
+async fn http_forward(
+    ctx: ContextRef,
+    queue: Sender<ContextRef>,
+    auth: &AuthData,
+    mut request: HttpRequest,
+) -> Result<(), Error> {
+    let (contexts, listener, source, extra, mut client) = {
+        let mut ctx = ctx.write().await;
+        (
+            ctx.global_state().clone(),
+            ctx.props().listener.clone(),
+            ctx.props().source,
+            // per connection data, like PROXY protocol addresses and peer credentials
+            ctx.props().extra.clone(),
+            ctx.take_client_stream(),
+        )
+    };
+    let mut first = Some(ctx);
+    loop {
+        let ctx = match first.take() {
+            Some(ctx) => ctx,
+            None => {
+                let ctx = contexts.create_context(listener.clone(), source).await;
+                let mut inner = ctx.write().await;
+                for (key, val) in &extra {
+                    inner.set_extra(key, val);
+                }
+                drop(inner);
+                ctx
+            }
+        };
+        if let Some(user) = authenticate(auth, &request, &mut client).await? {
+            ctx.write().await.set_extra("user", user);
+        } else {
+            ctx.on_error(err_msg("not authenticated")).await;
+            return Ok(());
+        }
+        let (target, resource) = match request.absolute_uri() {
+            Some(x) => x,
+            None => {
+                let error = err_msg(format!(
+                    "Invalid request: {} {}",
+                    request.method, request.resource
+                ));
+                return reject_request(&ctx, &mut client, error).await;
+            }
+        };
+        let method = request.method.clone();
+        let client_keep_alive = request.keep_alive();
+        let body = match request.body() {
+            Ok(x) => x,
+            Err(e) => return reject_request(&ctx, &mut client, e).await,
+        };
+        let expect_continue = request
+            .header("Expect", "")
+            .eq_ignore_ascii_case("100-continue");
+
+        let client_version = std::mem::replace(&mut request.version, "HTTP/1.1".into());
+        request.resource = resource;
+        strip_hop_by_hop_headers(&mut request.headers);
+        request
+            .headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case("Expect"));
+        // a proxy must replace Host with the authority of the absolute-form uri (RFC 7230 5.4)
+        request
+            .headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case("Host"));
+        request.headers.push(("Host".into(), target.to_string()));
+        // one request per upstream connection, so the context ends with the response
+        request.headers.push(("Connection".into(), "close".into()));
+
+        let (pipe, ctx_stream) = tokio::io::duplex(FORWARD_PIPE_SIZE);
+        let (tx, connected) = oneshot::channel();
+        ctx.write()
+            .await
+            .set_target(target)
+            .set_client_stream(make_buffered_stream(ctx_stream))
+            .set_callback(ForwardCallback(StdMutex::new(Some(tx))));
+        trace!("Request: {:?}", ctx);
+        if let Err(e) = ctx.enqueue(&queue).await {
+            debug!("failed to enqueue request: {}", e);
+        }
+
+        let mut server = make_buffered_stream(pipe);
+        // the request body is only read once the upstream is connected, a failed
+        // context leaves it unread so the client connection can not be reused
+        let mut forwarded = connected.await.unwrap_or(false);
+        if forwarded {
+            if expect_continue {
+                HttpResponse::new(100, "Continue")
+                    .write_to(&mut client)
+                    .await?;
+            }
+            if let Err(e) = request.write_to(&mut server).await {
+                debug!("failed to forward request: {}", e);
+                forwarded = false;
+            } else if let Err(e) = copy_body(&mut client, &mut server, body).await {
+                debug!("failed to forward request body: {}", e);
+                forwarded = false;
+            }
+        }
+
+        let mut response = loop {
+            let response = match HttpResponse::read_from(&mut server).await {
+                Ok(x) => x,
+                Err(e) => {
+                    HttpResponse::new(502, "Bad Gateway")
+                        .with_header("Content-Length", 0)
+                        .with_header("Connection", "close")
+                        .write_to(&mut client)
+                        .await?;
+                    return Err(e).context("read response");
+                }
+            };
+            // informational responses are followed by the final one
+            if (100..200).contains(&response.code) && response.code != 101 {
+                response.write_to(&mut client).await?;
+            } else {
+                break response;
+            }
+        };
+        let body = response.body(&method)?;
+        let keep_alive = forwarded && client_keep_alive && body != HttpBody::UntilClose;
+        strip_hop_by_hop_headers(&mut response.headers);
+        response.version = "HTTP/1.1".into();
+        if !keep_alive {
+            response.headers.push(("Connection".into(), "close".into()));
+        } else if client_version.eq_ignore_ascii_case("HTTP/1.0") {
+            response
+                .headers
+                .push(("Connection".into(), "keep-alive".into()));
+        }
+        response.write_to(&mut client).await?;
+        copy_body(&mut server, &mut client, body).await?;
+        drop(server);
+
+        if !keep_alive {
+            return Ok(());
+        }
+        request = match HttpRequest::read_from(&mut client).await {
+            Ok(x) => x,
+            // client closed connection
+            Err(_) => return Ok(()),
+        };
+        trace!("request={:?}", request);
+    }
+}
+
+// Check Proxy-Authorization of the request, returns name of the user (empty if
+// not provided) or None after answering client with 407 if authentication failed.
+// answers a request that can not be forwarded, the client connection is closed after it
+async fn reject_request(
+    ctx: &ContextRef,
+    client: &mut IOBufStream,
+    error: Error,
+) -> Result<(), Error> {
+    HttpResponse::new(400, "Bad Request")
+        .with_header("Content-Length", 0)
+        .with_header("Connection", "close")
+        .write_to(client)
+        .await?;
+    ctx.on_error(error).await;
+    Ok(())
+}
+
+async fn authenticate(
+    auth: &AuthData,
+    request: &HttpRequest,
+    socket: &mut IOBufStream,
+) -> Result<Option<String>, Error> {
+    let user = parse_basic_auth(request.header("Proxy-Authorization", ""));
+    if auth.check(&user).await {
+        return Ok(Some(user.map(|x| x.0).unwrap_or_default()));
+    }
+    debug!("client not authenticated: {:?}", user.map(|x| x.0));
+    HttpResponse::new(407, "Proxy Authentication Required")
+        .with_header("Proxy-Authenticate", "Basic realm=\"redproxy\"")
+        .with_header("Content-Length", 0)
+        .with_header("Connection", "close")
+        .write_to(socket)
+        .await?;
+    Ok(None)
+}
+
+async fn write_error_response(ctx: &mut Context, error: Error) {
+    let socket = ctx.borrow_client_stream();
+    if socket.is_none() {
+        return;
+    }
+    let buf = format!("Error: {} Cause: {:?}", error, error.cause);
+    if let Err(e) = HttpResponse::new(503, "Service unavailable")
+        .with_header("Content-Type", "text/plain")
+        .with_header("Content-Length", buf.len())
+        .with_header("Connection", "close")
+        .write_with_body(socket.unwrap(), buf.as_bytes())
+        .await
+    {
+        warn!("failed to send response: {}", e)
+    }
+}
+
+// tells http_forward whether the upstream got connected
+struct ForwardCallback(StdMutex<Option<oneshot::Sender<bool>>>);
+impl ForwardCallback {
+    fn notify(&self, connected: bool) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            let _ = tx.send(connected);
+        }
+    }
+}
+#[async_trait]
+impl ContextCallback for ForwardCallback {
+    async fn on_connect(&self, _ctx: &mut Context) {
+        self.notify(true)
+    }
+    async fn on_error(&self, ctx: &mut Context, error: Error) {
+        write_error_response(ctx, error).await;
+        self.notify(false)
+    }
+}
+
 struct ConnectCallback;
 #[async_trait]
 impl ContextCallback for ConnectCallback {
@@ -162,19 +433,7 @@ impl ContextCallback for ConnectCallback {
         }
     }
     async fn on_error(&self, ctx: &mut Context, error: Error) {
-        let socket = ctx.borrow_client_stream();
-        if socket.is_none() {
-            return;
-        }
-        let buf = format!("Error: {} Cause: {:?}", error, error.cause);
-        if let Err(e) = HttpResponse::new(503, "Service unavailable")
-            .with_header("Content-Type", "text/plain")
-            .with_header("Content-Length", buf.as_bytes().len())
-            .write_with_body(socket.unwrap(), buf.as_bytes())
-            .await
-        {
-            warn!("failed to send response: {}", e)
-        }
+        write_error_response(ctx, error).await
     }
 }
 
@@ -204,18 +463,82 @@ impl ContextCallback for FrameChannelCallback {
         }
     }
     async fn on_error(&self, ctx: &mut Context, error: Error) {
-        let socket = ctx.borrow_client_stream();
-        if socket.is_none() {
-            return;
-        }
-        let buf = format!("Error: {} Cause: {:?}", error, error.cause);
-        if let Err(e) = HttpResponse::new(503, "Service unavailable")
-            .with_header("Content-Type", "text/plain")
-            .with_header("Content-Length", buf.as_bytes().len())
-            .write_with_body(socket.unwrap(), buf.as_bytes())
+        write_error_response(ctx, error).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::GlobalState;
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc::channel,
+    };
+
+    // handshakes a client connection sending `request`,
+    // returns the first context and the client end of the connection
+    async fn handshake(
+        request: &str,
+    ) -> (
+        ContextRef,
+        tokio::io::DuplexStream,
+        tokio::sync::mpsc::Receiver<ContextRef>,
+    ) {
+        let state = Arc::new(GlobalState::default());
+        let ctx = state
+            .create_context("http".into(), "127.0.0.1:1234".parse().unwrap())
+            .await;
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        ctx.write()
             .await
-        {
-            warn!("failed to send response: {}", e)
-        }
+            .set_client_stream(make_buffered_stream(server));
+        let (tx, rx) = channel(1);
+        let handshake = ctx.clone();
+        tokio::spawn(async move {
+            h11c_handshake(handshake, tx, &AuthData::default(), |_, _| async {
+                bail!("not supported")
+            })
+            .await
+        });
+        (ctx, client, rx)
+    }
+
+    #[tokio::test]
+    async fn forward_bad_body_length() {
+        let (ctx, mut client, _rx) =
+            handshake("GET http://example.com/ HTTP/1.1\r\nContent-Length: x\r\n\r\n").await;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(ctx.read().await.props().error.is_some());
+    }
+
+    #[tokio::test]
+    async fn forward_replaces_host() {
+        let (_ctx, _client, mut rx) = handshake(
+            "GET http://example.com:8080/a HTTP/1.1\r\nHost: other.com\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let ctx = rx.recv().await.unwrap();
+        assert_eq!(ctx.read().await.target().to_string(), "example.com:8080");
+        ctx.on_connect().await;
+        let mut upstream = ctx.write().await.take_client_stream();
+        let request = HttpRequest::read_from(&mut upstream).await.unwrap();
+        assert_eq!(request.resource, "/a");
+        let hosts: Vec<_> = request
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Host"))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(hosts, vec!["example.com:8080"]);
     }
 }
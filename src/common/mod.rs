@@ -6,6 +6,8 @@ pub mod fragment;
 pub mod frames;
 pub mod h11c;
 pub mod http;
+pub mod proxy_protocol;
+pub mod sniff;
 pub mod socks;
 pub mod tls;
 pub mod udp;
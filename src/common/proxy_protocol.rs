@@ -0,0 +1,373 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use cidr::AnyIpCidr;
+use easy_error::{bail, ensure, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_FAM_TCP4: u8 = 0x11;
+const V2_FAM_TCP6: u8 = 0x21;
+const V2_FAM_UDP4: u8 = 0x12;
+const V2_FAM_UDP6: u8 = 0x22;
+
+/// Addresses of the original connection carried by a PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProxyHeader {
+    // both addresses in a header must be of the same family
+    fn addresses(&self) -> (SocketAddr, SocketAddr) {
+        let v6 = |addr: SocketAddr| match addr {
+            SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+            addr => addr,
+        };
+        if self.source.is_ipv4() == self.destination.is_ipv4() {
+            (self.source, self.destination)
+        } else {
+            (v6(self.source), v6(self.destination))
+        }
+    }
+
+    pub fn encode(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        match version {
+            ProxyProtocolVersion::V1 => self.encode_v1(),
+            ProxyProtocolVersion::V2 => self.encode_v2(false),
+        }
+    }
+
+    pub fn encode_v1(&self) -> Vec<u8> {
+        let (src, dst) = self.addresses();
+        format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if src.is_ipv4() { "TCP4" } else { "TCP6" },
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        )
+        .into_bytes()
+    }
+
+    /// Encodes as v2, `dgram` selects UDP instead of TCP as transport.
+    pub fn encode_v2(&self, dgram: bool) -> Vec<u8> {
+        let (src, dst) = self.addresses();
+        let mut ret = V2_SIGNATURE.to_vec();
+        ret.push(V2_CMD_PROXY);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                ret.push(if dgram { V2_FAM_UDP4 } else { V2_FAM_TCP4 });
+                ret.extend(12u16.to_be_bytes());
+                ret.extend(s.octets());
+                ret.extend(d.octets());
+            }
+            (IpAddr::V6(s), IpAddr::V6(d)) => {
+                ret.push(if dgram { V2_FAM_UDP6 } else { V2_FAM_TCP6 });
+                ret.extend(36u16.to_be_bytes());
+                ret.extend(s.octets());
+                ret.extend(d.octets());
+            }
+            _ => unreachable!(),
+        }
+        ret.extend(src.port().to_be_bytes());
+        ret.extend(dst.port().to_be_bytes());
+        ret
+    }
+}
+
+/// Listener option for accepting PROXY protocol (v1 and v2) headers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyProtocolConfig {
+    // address or cidr of peers which must send the header,
+    // other peers are served as plain clients. empty means every peer.
+    #[serde(default)]
+    trusted_peers: Vec<String>,
+    // time allowed for the header to arrive, unit: seconds
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(skip)]
+    cidrs: Vec<AnyIpCidr>,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// Streams the header can be peeked from, so data after it stays unread
+/// for the tls handshake or sniffing that may follow.
+#[async_trait]
+pub trait Peek: AsyncRead + Unpin + Send {
+    async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+}
+
+#[async_trait]
+impl Peek for &[u8] {
+    async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        Ok(len)
+    }
+}
+
+impl ProxyProtocolConfig {
+    pub fn init(&mut self) -> Result<(), Error> {
+        ensure!(self.timeout > 0, "timeout must greater than zero");
+        self.cidrs = self
+            .trusted_peers
+            .iter()
+            .map(|s| {
+                s.parse()
+                    .with_context(|| format!("invalid trusted peer: {}", s))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    fn trusted(&self, peer: IpAddr) -> bool {
+        self.cidrs.is_empty() || self.cidrs.iter().any(|c| c.contains(&peer))
+    }
+
+    /// Reads the header if `peer` is trusted to send one.
+    /// Returns None for untrusted peers and for LOCAL or UNKNOWN headers,
+    /// in which case the connection should be treated as it is.
+    pub async fn accept<IO: Peek>(
+        &self,
+        io: &mut IO,
+        peer: SocketAddr,
+    ) -> Result<Option<ProxyHeader>, Error> {
+        if !self.trusted(super::try_map_v4_addr(peer).ip()) {
+            return Ok(None);
+        }
+        tokio::time::timeout(Duration::from_secs(self.timeout), read_header(io))
+            .await
+            .context("header timed out")
+            .and_then(|x| x)
+            .context("proxy protocol")
+    }
+}
+
+/// Reads a v1 or v2 header, consuming nothing beyond it.
+pub async fn read_header<IO: Peek>(io: &mut IO) -> Result<Option<ProxyHeader>, Error> {
+    let mut buf = [0u8; V1_MAX_LENGTH];
+    let mut last = 0;
+    loop {
+        let len = io.peek(&mut buf).await.context("read header")?;
+        ensure!(len > 0, "connection closed");
+        let head = &buf[..len];
+        if head.starts_with(V2_SIGNATURE) {
+            return read_v2(io).await;
+        }
+        if head.starts_with(V1_PREFIX) {
+            if let Some(pos) = head.windows(2).position(|x| x == b"\r\n") {
+                let mut line = vec![0u8; pos + 2];
+                io.read_exact(&mut line).await.context("read header")?;
+                return parse_v1(&line);
+            }
+            ensure!(len < V1_MAX_LENGTH, "v1 header too long");
+        } else if !V2_SIGNATURE.starts_with(head) && !V1_PREFIX.starts_with(head) {
+            bail!("header missing");
+        }
+        // peek returns immediately while there is unread data,
+        // wait a bit for the rest of the header to arrive
+        if len == last {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        last = len;
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<ProxyHeader>, Error> {
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("v1 header")?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip = |s: &str| -> Result<IpAddr, Error> {
+                let ip: IpAddr = s.parse().with_context(|| format!("bad address {}", s))?;
+                ensure!(
+                    ip.is_ipv4() == (proto == "TCP4"),
+                    "address {} does not match {}",
+                    s,
+                    proto
+                );
+                Ok(ip)
+            };
+            let port = |s: &str| -> Result<u16, Error> {
+                s.parse().with_context(|| format!("bad port {}", s))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(src)?, port(sport)?),
+                destination: SocketAddr::new(ip(dst)?, port(dport)?),
+            }))
+        }
+        _ => bail!("malformed v1 header: {:?}", line),
+    }
+}
+
+async fn read_v2<IO: AsyncRead + Unpin>(io: &mut IO) -> Result<Option<ProxyHeader>, Error> {
+    let mut head = [0u8; 16];
+    io.read_exact(&mut head).await.context("read header")?;
+    let (cmd, fam) = (head[12], head[13]);
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut body = vec![0u8; len];
+    io.read_exact(&mut body).await.context("read addresses")?;
+    match cmd {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => bail!("unsupported v2 command: {:#x}", cmd),
+    }
+    // tlvs following the addresses are ignored
+    let (source, destination) = match fam {
+        V2_FAM_TCP4 => {
+            ensure!(len >= 12, "v2 header too short");
+            let ip = |b: &[u8]| IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(b).unwrap()));
+            let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+            (
+                SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+            )
+        }
+        V2_FAM_TCP6 => {
+            ensure!(len >= 36, "v2 header too short");
+            let ip = |b: &[u8]| IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()));
+            let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+            (
+                SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+            )
+        }
+        // unspec, udp or unix sockets, nothing we can use
+        _ => return Ok(None),
+    };
+    Ok(Some(ProxyHeader {
+        source,
+        destination,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> (Result<Option<ProxyHeader>, Error>, &[u8]) {
+        let ret = read_header(&mut data).await;
+        (ret, data)
+    }
+
+    fn header(src: &str, dst: &str) -> Option<ProxyHeader> {
+        Some(ProxyHeader {
+            source: src.parse().unwrap(),
+            destination: dst.parse().unwrap(),
+        })
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (ret, rest) = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /").await;
+        assert_eq!(
+            ret.unwrap(),
+            header("192.168.0.1:56324", "192.168.0.11:443")
+        );
+        assert_eq!(rest, b"GET /");
+
+        let (ret, _) = read(b"PROXY TCP6 ::1 ::2 1 2\r\n").await;
+        assert_eq!(ret.unwrap(), header("[::1]:1", "[::2]:2"));
+
+        let (ret, rest) = read(b"PROXY UNKNOWN\r\n\x05").await;
+        assert_eq!(ret.unwrap(), None);
+        assert_eq!(rest, b"\x05");
+
+        assert!(read(b"PROXY TCP4 ::1 ::2 1 2\r\n").await.0.is_err());
+        assert!(read(b"PROXY TCP4 1.1.1.1 2.2.2.2 1\r\n").await.0.is_err());
+        assert!(read(&[b'P'; 200]).await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_CMD_PROXY, V2_FAM_TCP4, 0, 15]);
+        data.extend([10, 0, 0, 1, 10, 0, 0, 2, 0x12, 0x34, 0x01, 0xbb]);
+        // a tlv to be skipped
+        data.extend([0x04, 0, 0]);
+        data.extend(b"rest");
+        let (ret, rest) = read(&data).await;
+        assert_eq!(ret.unwrap(), header("10.0.0.1:4660", "10.0.0.2:443"));
+        assert_eq!(rest, b"rest");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_CMD_PROXY, V2_FAM_TCP6, 0, 36]);
+        data.extend(Ipv6Addr::LOCALHOST.octets());
+        data.extend("fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend([0, 80, 0, 81]);
+        let (ret, _) = read(&data).await;
+        assert_eq!(ret.unwrap(), header("[::1]:80", "[fe80::1]:81"));
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_CMD_LOCAL, 0, 0, 0]);
+        assert_eq!(read(&data).await.0.unwrap(), None);
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_CMD_PROXY, V2_FAM_TCP4, 0, 4, 1, 2, 3, 4]);
+        assert!(read(&data).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn encode() {
+        for (src, dst) in [
+            ("192.168.0.1:56324", "192.168.0.11:443"),
+            ("[2001:db8::1]:1", "[::2]:2"),
+        ] {
+            let h = header(src, dst).unwrap();
+            for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+                let data = h.encode(version);
+                let (ret, rest) = read(&data).await;
+                assert_eq!(ret.unwrap(), Some(h));
+                assert!(rest.is_empty());
+            }
+        }
+        let h = header("1.2.3.4:5", "[::1]:6").unwrap();
+        assert_eq!(
+            h.encode_v1(),
+            b"PROXY TCP6 ::ffff:1.2.3.4 ::1 5 6\r\n".to_vec()
+        );
+        let (ret, _) = read(&h.encode(ProxyProtocolVersion::V2)).await;
+        assert_eq!(ret.unwrap(), header("[::ffff:1.2.3.4]:5", "[::1]:6"));
+        // udp headers are not accepted by listeners
+        let (ret, _) = read(&h.encode_v2(true)).await;
+        assert_eq!(ret.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn trusted_peers() {
+        let mut cfg: ProxyProtocolConfig =
+            serde_yaml::from_str("trustedPeers: [10.0.0.0/8, '::1']").unwrap();
+        cfg.init().unwrap();
+        let mut data: &[u8] = b"hello world!";
+        let peer = "192.168.0.1:1234".parse().unwrap();
+        assert_eq!(cfg.accept(&mut data, peer).await.unwrap(), None);
+        assert_eq!(data, b"hello world!");
+        let peer = "10.1.2.3:1234".parse().unwrap();
+        assert!(cfg.accept(&mut data, peer).await.is_err());
+    }
+}
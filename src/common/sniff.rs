@@ -0,0 +1,269 @@
+use std::{net::IpAddr, time::Duration};
+
+use tokio::{net::TcpStream, time::Instant};
+use tracing::debug;
+
+use crate::context::{ContextRef, TargetAddress};
+
+// largest possible TLS record, plus its header
+const MAX_PEEK: usize = 16384 + 5;
+// clients of server-speaks-first protocols send nothing, don't keep them waiting
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    /// Server name of a TLS ClientHello.
+    Tls(String),
+    /// Host header of a HTTP request.
+    Http(String),
+    /// Not a protocol we understand, or it carries no name.
+    Unknown,
+    Incomplete,
+}
+
+/// Rewrites target of `ctx` to the host name sniffed from `socket`,
+/// the original target is kept in extras.
+pub async fn sniff_target(socket: &TcpStream, ctx: &ContextRef) {
+    let (protocol, host) = match sniff(socket).await {
+        Some(Sniffed::Tls(host)) => ("tls", host),
+        Some(Sniffed::Http(host)) => ("http", host),
+        _ => return,
+    };
+    if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+        return;
+    }
+    let mut ctx = ctx.write().await;
+    let target = ctx.target();
+    debug!("sniffed {} host {} for {}", protocol, host, target);
+    ctx.set_extra("original-target", &target)
+        .set_extra("sniffed-host", &host)
+        .set_extra("sniffed-protocol", protocol)
+        .set_target(TargetAddress::DomainPort(host, target.port()));
+}
+
+/// Peeks the first bytes sent by client for a host name, consuming nothing.
+async fn sniff(socket: &TcpStream) -> Option<Sniffed> {
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = vec![0u8; MAX_PEEK];
+    let mut last = 0;
+    loop {
+        let len = tokio::time::timeout_at(deadline, socket.peek(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        if len == 0 {
+            return None;
+        }
+        match parse(&buf[..len]) {
+            Sniffed::Incomplete if len < MAX_PEEK => {}
+            Sniffed::Incomplete | Sniffed::Unknown => return None,
+            ret => return Some(ret),
+        }
+        // peek returns immediately while there is unread data,
+        // wait a bit for the rest of it to arrive
+        if len == last {
+            tokio::time::sleep_until(deadline.min(Instant::now() + Duration::from_millis(10)))
+                .await;
+            if Instant::now() >= deadline {
+                return None;
+            }
+        }
+        last = len;
+    }
+}
+
+fn parse(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(0x16) => parse_tls(buf),
+        Some(b'A'..=b'Z') => parse_http(buf),
+        _ => Sniffed::Unknown,
+    }
+}
+
+// reads big endian integers and slices of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (ret, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(ret)
+    }
+    fn int(&mut self, n: usize) -> Option<usize> {
+        Some(self.bytes(n)?.iter().fold(0, |a, b| a << 8 | *b as usize))
+    }
+    // a vector prefixed with its length in `n` bytes
+    fn vec(&mut self, n: usize) -> Option<Reader<'a>> {
+        let len = self.int(n)?;
+        self.bytes(len).map(Reader)
+    }
+}
+
+fn parse_tls(buf: &[u8]) -> Sniffed {
+    let mut record = Reader(buf);
+    let header = match record.bytes(5) {
+        Some(x) => x,
+        None => return Sniffed::Incomplete,
+    };
+    // handshake record, major version 3
+    if header[1] != 3 {
+        return Sniffed::Unknown;
+    }
+    let len = (header[3] as usize) << 8 | header[4] as usize;
+    let body = match record.bytes(len) {
+        Some(x) => x,
+        None => return Sniffed::Incomplete,
+    };
+    let mut body = Reader(body);
+    // client hello
+    if body.int(1) != Some(1) {
+        return Sniffed::Unknown;
+    }
+    client_hello_sni(body)
+        .map(Sniffed::Tls)
+        .unwrap_or(Sniffed::Unknown)
+}
+
+fn client_hello_sni(mut body: Reader) -> Option<String> {
+    let mut hello = body.vec(3)?;
+    // version and random
+    hello.bytes(2 + 32)?;
+    // session id, cipher suites and compression methods
+    hello.vec(1)?;
+    hello.vec(2)?;
+    hello.vec(1)?;
+    let mut extensions = hello.vec(2)?;
+    while let Some(ext_type) = extensions.int(2) {
+        let mut ext = extensions.vec(2)?;
+        if ext_type != 0 {
+            continue;
+        }
+        let mut names = ext.vec(2)?;
+        while let Some(name_type) = names.int(1) {
+            let name = names.vec(2)?.0;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+fn parse_http(buf: &[u8]) -> Sniffed {
+    let mut lines = buf.split_inclusive(|x| *x == b'\n');
+    let request_line = lines.next().unwrap_or_default();
+    // a method is all uppercase letters followed by a space
+    let method_len = request_line
+        .iter()
+        .take_while(|x| x.is_ascii_uppercase())
+        .count();
+    if request_line.get(method_len) != Some(&b' ') {
+        return if method_len == request_line.len() && method_len < 16 {
+            Sniffed::Incomplete
+        } else {
+            Sniffed::Unknown
+        };
+    }
+    let mut complete = false;
+    for line in lines {
+        let line = match line.strip_suffix(b"\n") {
+            Some(x) => x.strip_suffix(b"\r").unwrap_or(x),
+            // not yet terminated
+            None => break,
+        };
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        let line = match std::str::from_utf8(line) {
+            Ok(x) => x,
+            Err(_) => return Sniffed::Unknown,
+        };
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("host") {
+                return Sniffed::Http(strip_port(value.trim()).to_owned());
+            }
+        }
+    }
+    if complete {
+        Sniffed::Unknown
+    } else {
+        Sniffed::Incomplete
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(v6) = host.strip_prefix('[') {
+        return v6.split(']').next().unwrap_or(v6);
+    }
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|x| x.is_ascii_digit()) => host,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut ext = vec![];
+        // an extension before sni, supported_versions
+        ext.extend([0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(sni) = sni {
+            let n = sni.len() as u16;
+            ext.extend([0x00, 0x00]);
+            ext.extend((n + 5).to_be_bytes());
+            ext.extend((n + 3).to_be_bytes());
+            ext.push(0);
+            ext.extend(n.to_be_bytes());
+            ext.extend(sni.as_bytes());
+        }
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0u8; 32]);
+        hello.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend((ext.len() as u16).to_be_bytes());
+        hello.extend(ext);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn tls() {
+        let data = client_hello(Some("www.example.com"));
+        assert_eq!(parse(&data), Sniffed::Tls("www.example.com".into()));
+        assert_eq!(parse(&data[..data.len() - 1]), Sniffed::Incomplete);
+        assert_eq!(parse(&data[..3]), Sniffed::Incomplete);
+        assert_eq!(parse(&client_hello(None)), Sniffed::Unknown);
+        assert_eq!(parse(b"\x16\x01\x00\x00\x00"), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn http() {
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nUser-Agent: x\r\nHost: example.com:8080\r\n\r\n"),
+            Sniffed::Http("example.com".into())
+        );
+        assert_eq!(
+            parse(b"POST /x HTTP/1.1\nhost: [::1]:80\n"),
+            Sniffed::Http("::1".into())
+        );
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nAccept: */*\r\n"),
+            Sniffed::Incomplete
+        );
+        assert_eq!(parse(b"GE"), Sniffed::Incomplete);
+        assert_eq!(parse(b"GET / HTTP/1.0\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(parse(b"SSH-2.0-OpenSSH_9.0\r\n"), Sniffed::Unknown);
+        assert_eq!(parse(b"\x05\x01\x00"), Sniffed::Unknown);
+    }
+}
@@ -223,6 +223,7 @@ impl<T> SocksRequest<T> {
     }
 }
 
+#[allow(dead_code)]
 pub struct NoAuth;
 #[async_trait]
 impl SocksAuthServer<()> for NoAuth {
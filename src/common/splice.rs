@@ -64,7 +64,7 @@ pub async fn async_splice(
                 }
             }
             Err(e) => break Err(io::Error::from_raw_os_error(e as i32)),
-            Ok(ret) => break Ok(ret as usize),
+            Ok(ret) => break Ok(ret),
         }
     }
 }
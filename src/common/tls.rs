@@ -164,8 +164,8 @@ impl TlsClientConfig {
             config.with_custom_certificate_verifier(Arc::new(WebPkiVerifier::new(root_store, None)))
         };
 
-        let config = if self.auth.is_some() {
-            let certs = self.auth.as_ref().unwrap().certs()?;
+        let config = if let Some(auth) = &self.auth {
+            let certs = auth.certs()?;
             config
                 .with_single_cert(certs.0, certs.1)
                 .context("failed to load certificate")?
@@ -1,7 +1,10 @@
 use easy_error::{Error, ResultExt};
 use serde::{Deserialize, Serialize};
 
-use crate::access_log::AccessLog;
+use crate::{
+    access_log::AccessLog, bandwidth::BandwidthLimits, connection_limits::ConnectionLimits,
+    quota::Quotas,
+};
 
 #[cfg(feature = "metrics")]
 use crate::metrics::MetricsServer;
@@ -21,6 +24,10 @@ pub struct Config {
     pub timeouts: Timeouts,
     #[serde(default)]
     pub io_params: IoParams,
+    #[serde(default)]
+    pub bandwidth: BandwidthLimits,
+    pub quotas: Option<Quotas>,
+    pub connection_limits: Option<ConnectionLimits>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,11 +47,17 @@ impl Default for IoParams {
 }
 
 impl Config {
-    pub async fn load(path: &str) -> Result<Self, Error> {
+    /// Reads the config file without interpreting it, the raw value is kept
+    /// around to diff against on reload.
+    pub async fn load_value(path: &str) -> Result<serde_yaml::Value, Error> {
         let s = tokio::fs::read(path).await.context("read file")?;
         let s = String::from_utf8(s).context("parse utf8")?;
         serde_yaml::from_str(&s).context("parse yaml")
     }
+
+    pub fn from_value(value: serde_yaml::Value) -> Result<Self, Error> {
+        serde_yaml::from_value(value).context("parse config")
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,6 +67,8 @@ pub struct Timeouts {
     pub idle: u64,
     #[serde(default = "default_timeout")]
     pub udp: u64,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown: u64,
 }
 
 impl Default for Timeouts {
@@ -61,6 +76,7 @@ impl Default for Timeouts {
         Timeouts {
             idle: default_timeout(),
             udp: default_timeout(),
+            shutdown: default_shutdown_timeout(),
         }
     }
 }
@@ -69,6 +85,10 @@ fn default_timeout() -> u64 {
     600
 }
 
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{connectors, listeners, rules};
@@ -76,7 +96,8 @@ mod tests {
     #[tokio::test]
     async fn test_load() {
         use super::*;
-        let cfg = Config::load("config.yaml").await.unwrap();
+        let cfg = Config::load_value("config.yaml").await.unwrap();
+        let cfg = Config::from_value(cfg).unwrap();
         let _listeners = listeners::from_config(&cfg.listeners).unwrap();
         let _connectors = connectors::from_config(&cfg.connectors).unwrap();
         let _rules = rules::from_config(&cfg.rules).unwrap();
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use easy_error::{bail, ensure, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::context::ContextProps;
+
+#[cfg(feature = "metrics")]
+lazy_static::lazy_static! {
+    static ref CONNECTIONS_REJECTED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "connections_rejected",
+        "Number of requests rejected by connection limits.",
+        &["listener", "key"]
+    )
+    .unwrap();
+}
+
+/// Limits of concurrent connections and new connections per second,
+/// checked for every request before it is enqueued. A plain http proxy
+/// client sending several requests over one keep-alive connection takes a
+/// slot, and counts towards the rate, for each request.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionLimits {
+    // by source ip, not applied to unix socket clients
+    source: Option<Limit>,
+    // by authenticated user, not applied to anonymous clients
+    user: Option<Limit>,
+    // by listener, each listener is counted separately
+    listener: Option<Limit>,
+    #[serde(skip)]
+    counters: StdMutex<Counters>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Limit {
+    max_connections: Option<u32>,
+    // new connections per second
+    rate: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Source(IpAddr),
+    User(String),
+    Listener(String),
+}
+
+impl Key {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Source(_) => "source",
+            Self::User(_) => "user",
+            Self::Listener(_) => "listener",
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Source(x) => write!(f, "source {}", x),
+            Self::User(x) => write!(f, "user {}", x),
+            Self::Listener(x) => write!(f, "listener {}", x),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    entries: HashMap<Key, Entry>,
+    // second of the last sweep of idle entries
+    swept: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    active: u32,
+    // connections accepted during `second`
+    second: u64,
+    count: u32,
+}
+
+/// Holds a connection slot, released on drop.
+pub struct ConnectionGuard {
+    limits: Arc<ConnectionLimits>,
+    keys: Vec<Key>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counters = self.limits.counters.lock().unwrap();
+        for key in &self.keys {
+            if let Some(entry) = counters.entries.get_mut(key) {
+                entry.active -= 1;
+            }
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn init(&self) -> Result<(), Error> {
+        for limit in [&self.source, &self.user, &self.listener]
+            .into_iter()
+            .flatten()
+        {
+            ensure!(
+                limit.max_connections != Some(0),
+                "maxConnections must greater than zero"
+            );
+            ensure!(limit.rate != Some(0), "rate must greater than zero");
+        }
+        Ok(())
+    }
+
+    /// Takes a slot for the request described by `props`, fails if any
+    /// of the limits is reached.
+    pub fn acquire(self: &Arc<Self>, props: &ContextProps) -> Result<ConnectionGuard, Error> {
+        let second = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let ret = self.acquire_at(props, second);
+        #[cfg(feature = "metrics")]
+        if let Err((key, _)) = &ret {
+            CONNECTIONS_REJECTED
+                .with_label_values(&[props.listener.as_str(), key])
+                .inc();
+        }
+        ret.map_err(|(_, e)| e)
+    }
+
+    fn acquire_at(
+        self: &Arc<Self>,
+        props: &ContextProps,
+        second: u64,
+    ) -> Result<ConnectionGuard, (&'static str, Error)> {
+        let mut keys = vec![];
+        if let Some(limit) = self.source {
+            let ip = props.source.ip();
+            if !ip.is_unspecified() {
+                keys.push((Key::Source(ip), limit));
+            }
+        }
+        if let Some(limit) = self.user {
+            if let Some(user) = props.extra.get("user").filter(|x| !x.is_empty()) {
+                keys.push((Key::User(user.clone()), limit));
+            }
+        }
+        if let Some(limit) = self.listener {
+            keys.push((Key::Listener(props.listener.clone()), limit));
+        }
+
+        let mut counters = self.counters.lock().unwrap();
+        if counters.swept != second {
+            counters.swept = second;
+            counters
+                .entries
+                .retain(|_, e| e.active > 0 || e.second == second);
+        }
+        for (key, limit) in &keys {
+            if let Some(entry) = counters.entries.get(key) {
+                let check = || {
+                    if let Some(max) = limit.max_connections {
+                        if entry.active >= max {
+                            bail!("too many connections of {}", key);
+                        }
+                    }
+                    if let Some(rate) = limit.rate {
+                        if entry.second == second && entry.count >= rate {
+                            bail!("connection rate exceeded for {}", key);
+                        }
+                    }
+                    Ok(())
+                };
+                check().map_err(|e| (key.kind(), e))?;
+            }
+        }
+        for (key, _) in &keys {
+            let entry = counters.entries.entry(key.clone()).or_default();
+            entry.active += 1;
+            if entry.second != second {
+                entry.second = second;
+                entry.count = 0;
+            }
+            entry.count += 1;
+        }
+        Ok(ConnectionGuard {
+            limits: self.clone(),
+            keys: keys.into_iter().map(|(key, _)| key).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props(source: &str, user: &str) -> ContextProps {
+        let mut ret = ContextProps {
+            listener: "http".into(),
+            source: source.parse().unwrap(),
+            ..Default::default()
+        };
+        ret.extra.insert("user".into(), user.into());
+        ret
+    }
+
+    #[test]
+    fn limits() {
+        let limits: ConnectionLimits = serde_yaml::from_str(
+            "{source: {maxConnections: 2}, user: {rate: 3}, listener: {maxConnections: 10}}",
+        )
+        .unwrap();
+        limits.init().unwrap();
+        let limits = Arc::new(limits);
+        let a = props("10.0.0.1:1000", "");
+        let g1 = limits.acquire_at(&a, 1).unwrap();
+        let _g2 = limits.acquire_at(&a, 1).unwrap();
+        let e = limits.acquire_at(&a, 1).err().unwrap();
+        assert_eq!(e.0, "source");
+        assert_eq!(e.1.ctx, "too many connections of source 10.0.0.1");
+        drop(g1);
+        let _g3 = limits.acquire_at(&a, 1).unwrap();
+
+        // unix socket clients have no source limit
+        let alice = props("0.0.0.0:0", "alice");
+        let guards: Vec<_> = (0..3)
+            .map(|_| limits.acquire_at(&alice, 1).unwrap())
+            .collect();
+        drop(guards);
+        let e = limits.acquire_at(&alice, 1).err().unwrap();
+        assert_eq!(e.1.ctx, "connection rate exceeded for user alice");
+        assert!(limits.acquire_at(&alice, 2).is_ok());
+    }
+}
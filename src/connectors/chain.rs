@@ -0,0 +1,202 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use easy_error::{ensure, err_msg, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{ConnectOptions, Connector, ConnectorRef};
+use crate::{
+    context::{ContextRef, Feature},
+    GlobalState,
+};
+
+/// Reaches the target through a list of connectors, each of them
+/// tunnelled through the previous one.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainConnector {
+    name: String,
+    connectors: Vec<String>,
+}
+
+pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
+    ConnectOptions::reject(value)?;
+    let ret: ChainConnector = serde_yaml::from_value(value.clone()).context("parse config")?;
+    Ok(Box::new(ret))
+}
+
+#[async_trait]
+impl Connector for ChainConnector {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn references(&self) -> Vec<&str> {
+        self.connectors.iter().map(String::as_str).collect()
+    }
+
+    async fn verify(&self, connectors: &HashMap<String, Arc<dyn Connector>>) -> Result<(), Error> {
+        ensure!(
+            self.connectors.len() >= 2,
+            "chain requires at least two connectors"
+        );
+        for (i, n) in self.connectors.iter().enumerate() {
+            ensure!(n != &self.name, "connector can not chain itself");
+            let c = connectors
+                .get(n)
+                .ok_or_else(|| err_msg(format!("connector not defined: {}", n)))?;
+            // every hop but the first one is reached through the previous hop
+            ensure!(
+                i == 0 || c.server_address().is_some(),
+                "connector {} can not be chained",
+                n
+            );
+        }
+        super::check_loops(&self.name, connectors)
+    }
+
+    async fn connect(
+        self: Arc<Self>,
+        state: Arc<GlobalState>,
+        ctx: ContextRef,
+    ) -> Result<(), Error> {
+        let mut hops = Vec::with_capacity(self.connectors.len());
+        for name in &self.connectors {
+            let conn = state
+                .connectors
+                .read()
+                .await
+                .get(name)
+                .cloned()
+                .ok_or_else(|| err_msg(format!("connector not defined: {}", name)))?;
+            hops.push(conn);
+        }
+        let source = ctx.read().await.props().source;
+
+        // each hop connects to the server of the next one,
+        // over the stream established by the previous one
+        let mut transport = None;
+        for (i, pair) in hops.windows(2).enumerate() {
+            let (hop, next) = (&pair[0], &pair[1]);
+            let target = next
+                .server_address()
+                .ok_or_else(|| err_msg(format!("connector {} can not be chained", next.name())))?;
+            debug!("{}: {} connecting to {}", self.name, hop.name(), target);
+            let hop_ctx = state
+                .contexts
+                .create_detached_context(self.name.to_owned(), source);
+            hop_ctx
+                .write()
+                .await
+                .set_target(target)
+                .set_feature(Feature::TcpForward);
+            match transport.take() {
+                None => super::connect(hop.clone(), state.clone(), hop_ctx.clone()).await,
+                Some((stream, local, remote)) => {
+                    hop.clone()
+                        .connect_over(state.clone(), hop_ctx.clone(), stream, local, remote)
+                        .await
+                }
+            }
+            .with_context(|| format!("chain hop {}: {}", i, hop.name()))?;
+            let mut hop_ctx = hop_ctx.write().await;
+            let stream = hop_ctx
+                .take_server_stream()
+                .ok_or_else(|| err_msg(format!("connector {} has no stream", hop.name())))?;
+            transport = Some((stream, hop_ctx.local_addr(), hop_ctx.server_addr()));
+        }
+
+        let last = hops.last().unwrap();
+        let (stream, local, remote) = transport.unwrap();
+        last.clone()
+            .connect_over(state, ctx, stream, local, remote)
+            .await
+            .with_context(|| format!("chain hop {}: {}", hops.len() - 1, last.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::test_state;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn verify() {
+        let err = |s| async move { test_state(s).await.err().unwrap().ctx };
+        assert_eq!(
+            err("[{name: direct}, {name: c, type: chain, connectors: [direct]}]").await,
+            "chain requires at least two connectors"
+        );
+        assert_eq!(
+            err("[{name: direct}, {name: c, type: chain, connectors: [direct, c]}]").await,
+            "connector can not chain itself"
+        );
+        assert_eq!(
+            err("[{name: direct}, {name: c, type: chain, connectors: [direct, http]}]").await,
+            "connector not defined: http"
+        );
+        assert_eq!(
+            err("[{name: direct}, {name: c, type: chain, connectors: [direct, direct]}]").await,
+            "connector direct can not be chained"
+        );
+    }
+
+    // hops are connected in order, and the last one reaches the target
+    #[tokio::test]
+    async fn connect() {
+        // http proxy answering a single CONNECT request, then echoing
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = proxy.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut socket = BufReader::new(proxy.accept().await.unwrap().0);
+            let mut request = String::new();
+            while !request.ends_with("\r\n\r\n") {
+                socket.read_line(&mut request).await.unwrap();
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+            request
+        });
+
+        let state = test_state(&format!(
+            "[{{name: direct}}, {{name: http, server: 127.0.0.1, port: {}}}, \
+             {{name: c, type: chain, connectors: [direct, http]}}]",
+            port
+        ))
+        .await
+        .unwrap();
+        let chain = state.connectors.read().await.get("c").cloned().unwrap();
+        let ctx = state
+            .contexts
+            .create_context("test".into(), "127.0.0.1:1234".parse().unwrap())
+            .await;
+        ctx.write()
+            .await
+            .set_target("example.com:80".parse().unwrap())
+            .set_feature(Feature::TcpForward);
+        chain.connect(state.clone(), ctx.clone()).await.unwrap();
+
+        let mut stream = ctx.write().await.take_server_stream().unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("CONNECT example.com:80 HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+    }
+}
@@ -1,25 +1,37 @@
 use std::{
+    future::Future,
     io::ErrorKind,
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use async_trait::async_trait;
 use chashmap_async::CHashMap;
-use easy_error::{bail, Error, ResultExt};
+use easy_error::{err_msg, Error, ResultExt};
+use futures::{
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt,
+};
 use serde::{Deserialize, Serialize};
-use tokio::net::{TcpSocket, UdpSocket};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpSocket, TcpStream, UdpSocket},
+    time::Instant,
+};
 use tracing::{debug, trace};
 
-use super::ConnectorRef;
+use super::{ConnectOptions, ConnectorRef};
 use crate::{
     common::{
-        dns::{AddressFamily, DnsConfig},
+        dns::{AddressFamily, DnsConfig, Resolved},
         frames::{Frame, FrameIO, FrameReader, FrameWriter},
-        into_unspecified, set_keepalive,
+        into_unspecified,
+        proxy_protocol::{ProxyHeader, ProxyProtocolVersion},
+        set_keepalive,
         udp::udp_socket,
     },
-    context::{make_buffered_stream, ContextRef, Feature, TargetAddress},
+    context::{make_buffered_stream, ContextRef, ContextRefOps, Feature, TargetAddress},
     GlobalState,
 };
 
@@ -27,12 +39,15 @@ use crate::{
 #[serde(rename_all = "camelCase")]
 pub struct DirectConnector {
     name: String,
+    #[serde(flatten)]
+    options: ConnectOptions,
     bind: Option<IpAddr>,
     #[serde(default)]
     dns: Arc<DnsConfig>,
     fwmark: Option<u32>,
     #[serde(default = "default_keepalive")]
     keepalive: bool,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
     #[serde(skip)]
     udp_binds: Arc<CHashMap<String, SocketAddr>>,
 }
@@ -41,6 +56,11 @@ fn default_keepalive() -> bool {
     true
 }
 
+// delay before racing the next address, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// time given to the preferred family to answer after the other one, RFC 8305 section 3
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+
 pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
     let ret: DirectConnector = serde_yaml::from_value(value.clone()).context("parse config")?;
     Ok(Box::new(ret))
@@ -49,6 +69,7 @@ pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
 #[async_trait]
 impl super::Connector for DirectConnector {
     async fn init(&mut self) -> Result<(), Error> {
+        self.options.init()?;
         let dns = Arc::get_mut(&mut self.dns).unwrap();
         dns.init()?;
         if let Some(addr) = self.bind {
@@ -66,8 +87,17 @@ impl super::Connector for DirectConnector {
         self.name.as_str()
     }
 
+    fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
     fn features(&self) -> &[Feature] {
-        &[Feature::TcpForward, Feature::UdpForward, Feature::UdpBind]
+        &[
+            Feature::TcpForward,
+            Feature::TcpBind,
+            Feature::UdpForward,
+            Feature::UdpBind,
+        ]
     }
 
     async fn connect(
@@ -77,6 +107,46 @@ impl super::Connector for DirectConnector {
     ) -> Result<(), Error> {
         let target = ctx.read().await.target();
         trace!("connecting to {}", target);
+
+        let feature = ctx.read().await.feature();
+        if feature == Feature::TcpForward {
+            // name resolution is part of connecting, and counted in connectTimeout
+            let connect = async {
+                let answers = match &target {
+                    TargetAddress::SocketAddr(addr) => stream::iter([Ok(Resolved {
+                        preferred: true,
+                        addrs: vec![*addr],
+                    })])
+                    .boxed(),
+                    TargetAddress::DomainPort(domain, port) => {
+                        self.dns.lookup_host_stream(domain.as_str(), *port)
+                    }
+                    _ => unreachable!(),
+                };
+                happy_eyeballs(&self.name, answers, |x| self.connect_tcp(x)).await
+            };
+            let mut server = self.options.connect(&self.name, connect).await?;
+            let local = server.local_addr().context("local_addr")?;
+            let remote = server.peer_addr().context("peer_addr")?;
+            if self.keepalive {
+                set_keepalive(&server)?;
+            }
+            if let Some(version) = self.send_proxy_protocol {
+                let header = proxy_header(&ctx, remote).await.encode(version);
+                server
+                    .write_all(&header)
+                    .await
+                    .context("send proxy protocol header")?;
+            }
+            ctx.write()
+                .await
+                .set_server_stream(make_buffered_stream(server))
+                .set_local_addr(local)
+                .set_server_addr(remote);
+            trace!("connected to {:?} via {}", target, remote);
+            return Ok(());
+        }
+
         let remote = match &target {
             TargetAddress::SocketAddr(addr) => *addr,
             TargetAddress::DomainPort(domain, port) => {
@@ -87,30 +157,51 @@ impl super::Connector for DirectConnector {
 
         trace!("target resolved to {}", remote);
 
-        let feature = ctx.read().await.feature();
         match feature {
-            Feature::TcpForward => {
-                let server = if remote.is_ipv4() {
+            Feature::TcpForward => unreachable!(),
+            Feature::TcpBind => {
+                let local = SocketAddr::new(self.bind_addr_for(remote), 0);
+                let listener = if local.is_ipv4() {
                     TcpSocket::new_v4().context("socket")?
                 } else {
                     TcpSocket::new_v6().context("socket")?
                 };
-                if let Some(bind) = self.bind {
-                    server.bind(SocketAddr::new(bind, 0)).context("bind")?;
-                }
-                let server = server.connect(remote).await.context("connect")?;
-                let local = server.local_addr().context("local_addr")?;
-                let remote = server.peer_addr().context("peer_addr")?;
+                listener.bind(local).context("bind")?;
+                set_fwmark(&listener, self.fwmark)?;
+                let listener = listener.listen(1).context("listen")?;
+                let local = listener.local_addr().context("local_addr")?;
+                trace!("waiting for incoming connection on {}", local);
+                ctx.on_bind_listen(local.into()).await;
+
+                let timeout = ctx.read().await.idle_timeout();
+                // as RFC 1928 suggests, only the host named in request may connect
+                let accept = async {
+                    loop {
+                        let (server, peer) = listener.accept().await.context("accept")?;
+                        if remote.ip().is_unspecified() || peer.ip() == remote.ip() {
+                            return Ok::<_, Error>((server, peer));
+                        }
+                        debug!(
+                            "{}: dropped connection from {}, expecting {}",
+                            self.name,
+                            peer,
+                            remote.ip()
+                        );
+                    }
+                };
+                let (server, remote) = tokio::time::timeout(timeout, accept)
+                    .await
+                    .map_err(|_| err_msg("timeout waiting for incoming connection"))??;
                 if self.keepalive {
                     set_keepalive(&server)?;
                 }
-                set_fwmark(&server, self.fwmark)?;
                 ctx.write()
                     .await
                     .set_server_stream(make_buffered_stream(server))
                     .set_local_addr(local)
-                    .set_server_addr(remote);
-                trace!("connected to {:?}", target);
+                    .set_server_addr(remote)
+                    .set_extra("tcp-bind-peer", remote);
+                trace!("accepted connection from {:?}", remote);
             }
             Feature::UdpForward | Feature::UdpBind => {
                 let local = if let Some(bind) = self.bind {
@@ -137,9 +228,16 @@ impl super::Connector for DirectConnector {
                 let server = udp_socket(local, Some(remote), false).context("setup socket")?;
                 let local = server.local_addr().context("local_addr")?;
                 set_fwmark(&server, self.fwmark)?;
+                // v1 has no way to describe datagrams
+                let proxy_source = if self.send_proxy_protocol == Some(ProxyProtocolVersion::V2) {
+                    Some(proxy_header(&ctx, remote).await.source)
+                } else {
+                    None
+                };
+                let frames = setup_session(server, remote, self.dns.clone(), proxy_source);
                 ctx.write()
                     .await
-                    .set_server_frames(setup_session(server, remote, self.dns.clone()))
+                    .set_server_frames(frames)
                     .set_local_addr(local)
                     .set_server_addr(remote)
                     .set_extra("udp-bind-address", local.to_string());
@@ -149,19 +247,159 @@ impl super::Connector for DirectConnector {
                 }
                 trace!("connected to {:?}", target);
             }
-            x => bail!("not supported feature {:?}", x),
         }
         Ok(())
     }
 }
 
+impl DirectConnector {
+    async fn connect_tcp(&self, remote: SocketAddr) -> Result<TcpStream, Error> {
+        let server = if remote.is_ipv4() {
+            TcpSocket::new_v4().context("socket")?
+        } else {
+            TcpSocket::new_v6().context("socket")?
+        };
+        if let Some(bind) = self.bind {
+            server.bind(SocketAddr::new(bind, 0)).context("bind")?;
+        }
+        let server = server
+            .connect(remote)
+            .await
+            .with_context(|| format!("connect to {}", remote))?;
+        set_fwmark(&server, self.fwmark)?;
+        Ok(server)
+    }
+
+    // Pick the address which a BIND listener should be bound to, so that the address
+    // reported back to client is reachable from the expected peer.
+    fn bind_addr_for(&self, peer: SocketAddr) -> IpAddr {
+        if let Some(bind) = self.bind {
+            return bind;
+        }
+        let unspecified = into_unspecified(peer);
+        if peer.ip().is_unspecified() || peer.port() == 0 {
+            return unspecified.ip();
+        }
+        // "connecting" an udp socket sends nothing, but makes kernel select the outgoing address
+        std::net::UdpSocket::bind(unspecified)
+            .and_then(|s| s.connect(peer).and_then(|_| s.local_addr()))
+            .map(|addr| addr.ip())
+            .unwrap_or_else(|_| unspecified.ip())
+    }
+}
+
+// Connects to addresses in order as they are resolved, starting next attempt
+// once the previous one failed or didn't complete in CONNECTION_ATTEMPT_DELAY,
+// first established connection wins (RFC 8305).
+async fn happy_eyeballs<T, S, F, Fut>(name: &str, answers: S, connect: F) -> Result<T, Error>
+where
+    S: Stream<Item = Result<Resolved, Error>>,
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let answers = answers.fuse();
+    tokio::pin!(answers);
+    let mut resolving = true;
+    // set while the preferred family is given RESOLUTION_DELAY to answer
+    // after the other one, before connecting to the other family
+    let mut hold: Option<Instant> = None;
+    let mut preferred_resolved = false;
+    // addresses not tried yet, in the order to try them
+    let mut remotes = Vec::new();
+    let mut attempts = FuturesUnordered::new();
+    let mut next_attempt = Instant::now();
+    let mut last_error = None;
+    loop {
+        let start_at = hold.map_or(next_attempt, |x| x.max(next_attempt));
+        if !remotes.is_empty() && Instant::now() >= start_at {
+            hold = None;
+            attempts.push(connect(remotes.remove(0)));
+            next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+            continue;
+        }
+        if attempts.is_empty() && remotes.is_empty() && !resolving {
+            break;
+        }
+        tokio::select! {
+            Some(ret) = attempts.next() => match ret {
+                Ok(server) => return Ok(server),
+                Err(e) => {
+                    debug!("{}: {} cause: {:?}", name, e, e.cause);
+                    last_error = Some(e);
+                    next_attempt = Instant::now();
+                }
+            },
+            answer = answers.next(), if resolving => match answer {
+                Some(Ok(answer)) => {
+                    trace!("{}: resolved {:?}", name, answer);
+                    let pending = std::mem::take(&mut remotes);
+                    remotes = if answer.preferred {
+                        preferred_resolved = true;
+                        hold = None;
+                        interleave(answer.addrs, pending)
+                    } else {
+                        if !preferred_resolved && attempts.is_empty() {
+                            hold = Some(Instant::now() + RESOLUTION_DELAY);
+                        }
+                        interleave(pending, answer.addrs)
+                    };
+                }
+                Some(Err(e)) => {
+                    debug!("{}: {} cause: {:?}", name, e, e.cause);
+                    last_error = Some(e);
+                }
+                None => {
+                    resolving = false;
+                    hold = None;
+                }
+            },
+            _ = tokio::time::sleep_until(start_at), if !remotes.is_empty() => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| err_msg("no address to connect")))
+}
+
+fn interleave<T>(first: Vec<T>, second: Vec<T>) -> Vec<T> {
+    let mut ret = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ret,
+            (a, b) => ret.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// the original destination if the client came through a proxy, otherwise
+// the address client connected to. clients of unix sockets have neither,
+// the server we are connecting to is used then.
+async fn proxy_header(ctx: &ContextRef, server: SocketAddr) -> ProxyHeader {
+    let ctx = ctx.read().await;
+    let destination = ctx
+        .extra("proxy-protocol-destination")
+        .or_else(|| ctx.extra("listener-address"))
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(server);
+    ProxyHeader {
+        source: ctx.props().source,
+        destination,
+    }
+}
+
 use std::io::Result as IoResult;
-fn setup_session(socket: UdpSocket, target: SocketAddr, dns: Arc<DnsConfig>) -> FrameIO {
+fn setup_session(
+    socket: UdpSocket,
+    target: SocketAddr,
+    dns: Arc<DnsConfig>,
+    proxy_source: Option<SocketAddr>,
+) -> FrameIO {
     let socket = Arc::new(socket);
     let frames = DirectFrames {
         socket,
         target,
         dns,
+        proxy_source,
     };
     (Box::new(frames.clone()), Box::new(frames))
 }
@@ -171,6 +409,8 @@ struct DirectFrames {
     socket: Arc<UdpSocket>,
     target: SocketAddr,
     dns: Arc<DnsConfig>,
+    // prefix each datagram with a v2 header carrying this source
+    proxy_source: Option<SocketAddr>,
 }
 
 #[async_trait]
@@ -209,7 +449,17 @@ impl FrameWriter for DirectFrames {
             self.target
         };
         tracing::trace!("send udp frame: {:?}", frame);
-        self.socket.send_to(frame.body(), target).await?;
+        if let Some(source) = self.proxy_source {
+            let header = ProxyHeader {
+                source,
+                destination: target,
+            };
+            let mut buf = header.encode_v2(true);
+            buf.extend_from_slice(frame.body());
+            self.socket.send_to(&buf, target).await?;
+        } else {
+            self.socket.send_to(frame.body(), target).await?;
+        }
         Ok(frame.len())
     }
     async fn shutdown(&mut self) -> IoResult<()> {
@@ -236,3 +486,122 @@ pub fn set_fwmark<T>(_sk: &T, _mark: Option<u32>) -> Result<(), Error> {
     tracing::warn!("fwmark not supported on this platform");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::time::sleep;
+
+    // each address is connected after the delay given by its port,
+    // failing if the port is odd
+    async fn connect(x: SocketAddr) -> Result<u16, Error> {
+        sleep(Duration::from_millis(x.port().into())).await;
+        if x.port().is_multiple_of(2) {
+            Ok(x.port())
+        } else {
+            Err(err_msg(format!("failed {}", x.port())))
+        }
+    }
+
+    fn addrs(ip: &str, ports: &[u16]) -> Vec<SocketAddr> {
+        let ip: IpAddr = ip.parse().unwrap();
+        ports.iter().map(|&p| (ip, p).into()).collect()
+    }
+
+    async fn race(ports: &[u16]) -> (Result<u16, Error>, Duration) {
+        let answers = stream::iter([Ok(Resolved {
+            preferred: true,
+            addrs: addrs("127.0.0.1", ports),
+        })]);
+        let start = Instant::now();
+        let ret = happy_eyeballs("test", answers, connect).await;
+        (ret, start.elapsed())
+    }
+
+    // the preferred family (ipv6) is answered after `delay`,
+    // the other one right away
+    async fn race_answers(v6: &[u16], delay: u64, v4: &[u16]) -> (Result<u16, Error>, Duration) {
+        let v6 = Resolved {
+            preferred: true,
+            addrs: addrs("::1", v6),
+        };
+        let v4 = Resolved {
+            preferred: false,
+            addrs: addrs("127.0.0.1", v4),
+        };
+        let answers = stream::select(
+            async move {
+                sleep(Duration::from_millis(delay)).await;
+                Ok(v6)
+            }
+            .into_stream(),
+            stream::iter([Ok(v4)]),
+        );
+        let start = Instant::now();
+        let ret = happy_eyeballs("test", answers, |x| async move {
+            connect(x)
+                .await
+                .map(|port| if x.is_ipv6() { port + 1000 } else { port })
+        })
+        .await;
+        (ret, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_race() {
+        // the second address starts after 250ms and wins
+        let (ret, time) = race(&[1000, 100]).await;
+        assert_eq!(ret.unwrap(), 100);
+        assert_eq!(time, Duration::from_millis(350));
+        // a failure starts the next attempt without waiting
+        let (ret, time) = race(&[51, 100]).await;
+        assert_eq!(ret.unwrap(), 100);
+        assert_eq!(time, Duration::from_millis(151));
+        // the first address wins if it is fast enough
+        let (ret, time) = race(&[200, 10]).await;
+        assert_eq!(ret.unwrap(), 200);
+        assert_eq!(time, Duration::from_millis(200));
+        let (ret, _) = race(&[11, 21]).await;
+        assert_eq!(ret.unwrap_err().ctx, "failed 21");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_answers() {
+        // a black holed AAAA query delays connecting by RESOLUTION_DELAY only
+        let (ret, time) = race_answers(&[10], 60_000, &[100]).await;
+        assert_eq!(ret.unwrap(), 100);
+        assert_eq!(time, Duration::from_millis(150));
+        // AAAA answered within RESOLUTION_DELAY is tried first
+        let (ret, time) = race_answers(&[10], 20, &[100]).await;
+        assert_eq!(ret.unwrap(), 1010);
+        assert_eq!(time, Duration::from_millis(30));
+        // a late AAAA answer joins the race, CONNECTION_ATTEMPT_DELAY after the A attempt
+        let (ret, time) = race_answers(&[10], 100, &[1000]).await;
+        assert_eq!(ret.unwrap(), 1010);
+        assert_eq!(time, Duration::from_millis(310));
+        // a failed AAAA query is fine as long as A is answered
+        let answers = stream::select(
+            stream::iter([Err(err_msg("ipv6_lookup"))]),
+            stream::iter([Ok(Resolved {
+                preferred: false,
+                addrs: addrs("127.0.0.1", &[100]),
+            })]),
+        );
+        assert_eq!(happy_eyeballs("test", answers, connect).await.unwrap(), 100);
+    }
+
+    #[test]
+    fn interleave_families() {
+        let ips = |s: &str| -> Vec<IpAddr> { s.split(' ').map(|x| x.parse().unwrap()).collect() };
+        assert_eq!(
+            interleave(ips("::1 ::2 ::3"), ips("10.0.0.1")),
+            ips("::1 10.0.0.1 ::2 ::3")
+        );
+        assert_eq!(
+            interleave(ips("10.0.0.1 10.0.0.2"), ips("::1 ::2 ::3")),
+            ips("10.0.0.1 ::1 10.0.0.2 ::2 ::3")
+        );
+        assert_eq!(interleave(vec![], ips("::1")), ips("::1"));
+    }
+}
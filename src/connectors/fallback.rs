@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use easy_error::{ensure, err_msg, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{ConnectOptions, Connector, ConnectorRef};
+use crate::{
+    context::{ContextRef, Feature},
+    GlobalState,
+};
+
+/// Tries connectors in order until one of them connects.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FallbackConnector {
+    name: String,
+    connectors: Vec<String>,
+    // timeout of each attempt, unit: seconds
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    // features of any of the members, collected in verify
+    #[serde(skip)]
+    supported: StdMutex<Vec<Feature>>,
+}
+
+const FEATURES: [Feature; 4] = [
+    Feature::TcpForward,
+    Feature::TcpBind,
+    Feature::UdpForward,
+    Feature::UdpBind,
+];
+
+fn default_timeout() -> u64 {
+    10
+}
+
+pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
+    ConnectOptions::reject(value)?;
+    let ret: FallbackConnector = serde_yaml::from_value(value.clone()).context("parse config")?;
+    Ok(Box::new(ret))
+}
+
+#[async_trait]
+impl Connector for FallbackConnector {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    // members lacking the requested feature are skipped
+    fn has_feature(&self, feature: Feature) -> bool {
+        self.supported.lock().unwrap().contains(&feature)
+    }
+
+    fn references(&self) -> Vec<&str> {
+        self.connectors.iter().map(String::as_str).collect()
+    }
+
+    async fn init(&mut self) -> Result<(), Error> {
+        ensure!(self.timeout > 0, "timeout must greater than zero");
+        Ok(())
+    }
+
+    async fn verify(&self, connectors: &HashMap<String, Arc<dyn Connector>>) -> Result<(), Error> {
+        ensure!(!self.connectors.is_empty(), "connectors must not be empty");
+        for n in &self.connectors {
+            ensure!(n != &self.name, "connector can not fallback to itself");
+            ensure!(connectors.contains_key(n), "connector not defined: {}", n);
+        }
+        super::check_loops(&self.name, connectors)?;
+        let members: Vec<_> = self.connectors.iter().map(|n| &connectors[n]).collect();
+        for c in &members {
+            // members passing connections on collect their own features in verify
+            if !c.references().is_empty() {
+                c.verify(connectors).await?;
+            }
+        }
+        *self.supported.lock().unwrap() = FEATURES
+            .into_iter()
+            .filter(|&f| members.iter().any(|c| c.has_feature(f)))
+            .collect();
+        Ok(())
+    }
+
+    async fn connect(
+        self: Arc<Self>,
+        state: Arc<GlobalState>,
+        ctx: ContextRef,
+    ) -> Result<(), Error> {
+        let feature = ctx.read().await.feature();
+        let timeout = Duration::from_secs(self.timeout);
+        let mut attempts = Vec::new();
+        for name in &self.connectors {
+            let conn = state.connectors.read().await.get(name).cloned();
+            let conn = match conn {
+                Some(conn) if conn.has_feature(feature) => conn,
+                Some(_) => continue,
+                None => {
+                    attempts.push(format!("{}: not defined", name));
+                    continue;
+                }
+            };
+            debug!("{}: trying connector: {}", self.name, name);
+            ctx.write().await.set_connector(name.to_owned());
+            let ret = attempt(
+                timeout,
+                &ctx,
+                super::connect(conn, state.clone(), ctx.clone()),
+            )
+            .await;
+            match ret {
+                Ok(()) => {
+                    attempts.push(format!("{}: ok", name));
+                    ctx.write()
+                        .await
+                        .set_extra("fallback-attempts", attempts.join("; "));
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "{}: connector {} failed: {} cause: {:?}",
+                        self.name, name, e, e.cause
+                    );
+                    attempts.push(format!("{}: {}", name, e));
+                    if bind_listening(&ctx).await {
+                        break;
+                    }
+                }
+            }
+        }
+        let attempts = attempts.join("; ");
+        ctx.write().await.set_extra("fallback-attempts", &attempts);
+        if attempts.is_empty() {
+            Err(err_msg(format!(
+                "no connector supports feature: {}",
+                feature
+            )))
+        } else {
+            Err(err_msg(format!("all connectors failed: {}", attempts)))
+        }
+    }
+}
+
+// the client has been told where to connect, too late to switch
+async fn bind_listening(ctx: &ContextRef) -> bool {
+    ctx.read().await.extra("tcp-bind-address").is_some()
+}
+
+// Runs `connect` under `timeout`, unless it is a BIND listening already by then,
+// waiting for the peer is bounded by idle timeout instead.
+async fn attempt<F>(timeout: Duration, ctx: &ContextRef, connect: F) -> Result<(), Error>
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    tokio::pin!(connect);
+    if let Ok(ret) = tokio::time::timeout(timeout, &mut connect).await {
+        return ret;
+    }
+    // keep polling connect, it may hold the context lock
+    tokio::select! {
+        ret = &mut connect => ret,
+        listening = bind_listening(ctx) => if listening {
+            connect.await
+        } else {
+            Err(err_msg("connect timeout"))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::test_state;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn verify() {
+        let err = |s| async move { test_state(s).await.err().unwrap().ctx };
+        assert_eq!(
+            err("[{name: a, type: fallback, connectors: [a]}]").await,
+            "connector can not fallback to itself"
+        );
+        // reported from whichever of them is verified first
+        let loops = err("[{name: a, type: fallback, connectors: [b]}, \
+                         {name: b, type: loadbalance, connectors: [c]}, \
+                         {name: c, type: fallback, connectors: [a]}]")
+        .await;
+        assert!(loops.starts_with("connector loop: "), "{}", loops);
+        assert_eq!(loops.matches(" -> ").count(), 3, "{}", loops);
+        // loops not passing through the connector itself are found as well
+        assert!(err("[{name: a, type: fallback, connectors: [b]}, \
+                     {name: b, type: fallback, connectors: [c]}, \
+                     {name: c, type: loadbalance, connectors: [b]}]")
+        .await
+        .starts_with("connector loop: "));
+    }
+
+    #[tokio::test]
+    async fn features() {
+        let state = test_state(
+            "[{name: http, server: 127.0.0.1, port: 1}, {name: direct}, \
+              {name: a, type: fallback, connectors: [http]}, \
+              {name: b, type: fallback, connectors: [a, direct]}]",
+        )
+        .await
+        .unwrap();
+        let connectors = state.connectors.read().await;
+        assert!(connectors["a"].has_feature(Feature::UdpForward));
+        assert!(!connectors["a"].has_feature(Feature::TcpBind));
+        assert!(connectors["b"].has_feature(Feature::TcpBind));
+    }
+
+    #[tokio::test]
+    async fn connect() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        // nothing listens on the port of a closed listener
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let state = test_state(&format!(
+            "[{{name: http, server: 127.0.0.1, port: {}}}, {{name: direct}}, \
+              {{name: a, type: fallback, connectors: [http, direct]}}]",
+            port
+        ))
+        .await
+        .unwrap();
+        let conn = state.connectors.read().await["a"].clone();
+        let ctx = state
+            .contexts
+            .create_context("test".into(), "127.0.0.1:1234".parse().unwrap())
+            .await;
+        ctx.write().await.set_target(target.into());
+        conn.connect(state.clone(), ctx.clone()).await.unwrap();
+        server.accept().await.unwrap();
+        let ctx = ctx.read().await;
+        assert_eq!(ctx.props().connector.as_deref(), Some("direct"));
+        let attempts = ctx.extra("fallback-attempts").unwrap();
+        assert!(attempts.starts_with("http: "), "{}", attempts);
+        assert!(attempts.ends_with("; direct: ok"), "{}", attempts);
+    }
+
+    #[tokio::test]
+    async fn bind_after_timeout() {
+        let state = test_state(
+            "[{name: direct}, {name: a, type: fallback, connectors: [direct], timeout: 1}]",
+        )
+        .await
+        .unwrap();
+        let conn = state.connectors.read().await["a"].clone();
+        let ctx = state
+            .contexts
+            .create_context("test".into(), "127.0.0.1:1234".parse().unwrap())
+            .await;
+        ctx.write()
+            .await
+            .set_target(
+                "127.0.0.1:0"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into(),
+            )
+            .set_feature(Feature::TcpBind)
+            .set_idle_timeout(10);
+        let task = tokio::spawn(conn.connect(state.clone(), ctx.clone()));
+        let listen = loop {
+            if let Some(addr) = ctx.read().await.extra("tcp-bind-address") {
+                break addr.parse::<std::net::SocketAddr>().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        // the peer shows up after the attempt timeout has passed
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let _peer = tokio::net::TcpStream::connect(listen).await.unwrap();
+        task.await.unwrap().unwrap();
+        let ctx = ctx.read().await;
+        assert_eq!(ctx.extra("fallback-attempts").unwrap(), "direct: ok");
+    }
+}
@@ -1,4 +1,4 @@
-use std::{convert::TryFrom, sync::Arc};
+use std::{convert::TryFrom, net::SocketAddr, sync::Arc};
 
 use async_trait::async_trait;
 use easy_error::{err_msg, Error, ResultExt};
@@ -9,19 +9,34 @@ use tracing::trace;
 
 use crate::{
     common::{h11c::h11c_connect, set_keepalive, tls::TlsClientConfig},
-    context::{make_buffered_stream, ContextRef, Feature},
+    context::{make_buffered_stream, ContextRef, Feature, IOBufStream, TargetAddress},
     GlobalState,
 };
 
-use super::ConnectorRef;
+use super::{ConnectOptions, ConnectorRef};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct HttpConnector {
     name: String,
+    #[serde(flatten)]
+    options: ConnectOptions,
     server: String,
     port: u16,
     tls: Option<TlsClientConfig>,
+    auth: Option<HttpAuthData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpAuthData {
+    username: String,
+    password: String,
+}
+
+impl HttpAuthData {
+    pub fn credentials(&self) -> (String, String) {
+        (self.username.to_owned(), self.password.to_owned())
+    }
 }
 
 pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
@@ -35,7 +50,12 @@ impl super::Connector for HttpConnector {
         self.name.as_str()
     }
 
+    fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
     async fn init(&mut self) -> Result<(), Error> {
+        self.options.init()?;
         if let Some(Err(e)) = self.tls.as_mut().map(TlsClientConfig::init) {
             return Err(e);
         }
@@ -46,26 +66,62 @@ impl super::Connector for HttpConnector {
         &[Feature::TcpForward, Feature::UdpForward, Feature::UdpBind]
     }
 
+    fn server_address(&self) -> Option<TargetAddress> {
+        Some(super::server_address(&self.server, self.port))
+    }
+
     async fn connect(
         self: Arc<Self>,
-        _state: Arc<GlobalState>,
+        state: Arc<GlobalState>,
         ctx: ContextRef,
     ) -> Result<(), Error> {
-        let tls_insecure = self.tls.as_ref().map(|x| x.insecure).unwrap_or(false);
-        let tls_connector = self.tls.as_ref().map(|options| options.connector());
         trace!(
             "{} connecting to server {}:{}",
             self.name,
             self.server,
             self.port
         );
-        let server = TcpStream::connect((self.server.as_str(), self.port))
-            .await
-            .with_context(|| format!("failed to connect to upstream server: {}", self.server))?;
+        let server = self
+            .options
+            .connect(&self.name, async {
+                TcpStream::connect((self.server.as_str(), self.port))
+                    .await
+                    .with_context(|| {
+                        format!("failed to connect to upstream server: {}", self.server)
+                    })
+            })
+            .await?;
         let local = server.local_addr().context("local_addr")?;
         let remote = server.peer_addr().context("peer_addr")?;
         set_keepalive(&server)?;
+        let server = make_buffered_stream(server);
+        self.connect_over(state, ctx, server, local, remote).await
+    }
+
+    async fn connect_over(
+        self: Arc<Self>,
+        _state: Arc<GlobalState>,
+        ctx: ContextRef,
+        server: IOBufStream,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(), Error> {
+        self.options
+            .handshake(&self.name, self.handshake(ctx, server, local, remote))
+            .await
+    }
+}
 
+impl HttpConnector {
+    async fn handshake(
+        &self,
+        ctx: ContextRef,
+        server: IOBufStream,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(), Error> {
+        let tls_insecure = self.tls.as_ref().map(|x| x.insecure).unwrap_or(false);
+        let tls_connector = self.tls.as_ref().map(|options| options.connector());
         let server = if let Some(connector) = tls_connector {
             let domain = ServerName::try_from(self.server.as_str())
                 .or_else(|e| {
@@ -83,10 +139,11 @@ impl super::Connector for HttpConnector {
                     .context("tls connector error")?,
             )
         } else {
-            make_buffered_stream(server)
+            server
         };
 
-        h11c_connect(server, ctx, local, remote, "inline", |_| async {
+        let auth = self.auth.as_ref().map(HttpAuthData::credentials);
+        h11c_connect(server, ctx, local, remote, auth, "inline", |_| async {
             panic!("not supported")
         })
         .await?;
@@ -1,29 +1,52 @@
 use std::{
-    hash::Hash,
+    collections::HashMap,
+    hash::Hasher,
+    net::{Ipv4Addr, SocketAddr},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
     },
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
-use easy_error::{ensure, Error, ResultExt};
+use easy_error::{ensure, err_msg, Error, ResultExt};
 use milu::{
     parser::parse,
     script::{ScriptContext, Type, Value},
 };
 use rand::{prelude::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, info, warn};
 
-use super::{Connector, ConnectorRef};
-use crate::{context::ContextRef, rules::script_ext::create_context, GlobalState};
+use super::{ConnectOptions, Connector, ConnectorRef};
+use crate::{
+    context::{ContextRef, Feature, TargetAddress},
+    rules::script_ext::create_context,
+    GlobalState,
+};
+
+#[cfg(feature = "metrics")]
+lazy_static::lazy_static! {
+    static ref MEMBER_HEALTH: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "loadbalance_member_healthy",
+        "Health check result of load balanced connectors, 1 for healthy.",
+        &["connector", "member"]
+    )
+    .unwrap();
+    // instance publishing MEMBER_HEALTH series of each load balancer, and its members,
+    // a reloaded connector takes over the series while the old one is still alive
+    static ref HEALTH_OWNERS: StdMutex<HashMap<String, (u64, Vec<String>)>> = Default::default();
+}
+
+#[cfg(feature = "metrics")]
+static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct LoadBalanceConnector {
     name: String,
-    connectors: Vec<String>,
+    connectors: Vec<Member>,
 
     #[serde(
         alias = "algo",
@@ -32,34 +55,220 @@ pub struct LoadBalanceConnector {
     )]
     algorithm: Algorithm,
 
-    // for RoundRobin selection,
+    // for RoundRobin selection, current weights of smooth weighted round robin
     #[serde(skip)]
-    idx: AtomicUsize,
+    rr_weights: StdMutex<Vec<i64>>,
 
     #[serde(skip)]
     hash_by: Option<Value>,
+
+    health_check: Option<HealthCheck>,
+    // one for each of connectors
+    #[serde(skip)]
+    health: Vec<MemberHealth>,
+    #[serde(skip)]
+    stats: Vec<MemberStats>,
+    // tells instances of the same connector apart across reloads
+    #[cfg(feature = "metrics")]
+    #[serde(skip)]
+    id: u64,
+}
+
+/// A member connector, either a bare name or `{name, weight}`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum Member {
+    Name(String),
+    Weighted {
+        name: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Member {
+    fn name(&self) -> &str {
+        match self {
+            Self::Name(name) | Self::Weighted { name, .. } => name,
+        }
+    }
+
+    fn weight(&self) -> u32 {
+        match self {
+            Self::Name(_) => default_weight(),
+            Self::Weighted { weight, .. } => *weight,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+pub struct HealthCheck {
+    // address to connect to through each member
+    target: TargetAddress,
+    // unit: seconds
+    #[serde(default = "default_interval")]
+    interval: u64,
+    // unit: seconds
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    // consecutive successes to mark a member healthy
+    #[serde(default = "default_rise")]
+    rise: u32,
+    // consecutive failures to mark a member unhealthy
+    #[serde(default = "default_fall")]
+    fall: u32,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_rise() -> u32 {
+    2
+}
+
+fn default_fall() -> u32 {
+    3
+}
+
+#[derive(Debug)]
+struct MemberHealth {
+    healthy: AtomicBool,
+    successes: AtomicU32,
+    failures: AtomicU32,
+}
+
+impl Default for MemberHealth {
+    // members are considered healthy until probes say otherwise
+    fn default() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            successes: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl MemberHealth {
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    // returns Some(new state) if health state is changed
+    fn report(&self, ok: bool, check: &HealthCheck) -> Option<bool> {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            let n = self.successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if n >= check.rise && !self.healthy.swap(true, Ordering::Relaxed) {
+                return Some(true);
+            }
+        } else {
+            self.successes.store(0, Ordering::Relaxed);
+            let n = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if n >= check.fall && self.healthy.swap(false, Ordering::Relaxed) {
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+// a failed connect counts as a connect this slow
+const RTT_FAILURE_PENALTY: Duration = Duration::from_secs(5);
+// latency measured longer ago is forgotten, so that members
+// penalized for failures get tried again
+const RTT_EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct MemberStats {
+    // contexts connecting or connected through this member, only tracked for LeastConnection
+    active: Arc<AtomicUsize>,
+    // EWMA of connect latency and time of the last sample
+    rtt: StdMutex<Option<(Duration, Instant)>>,
+}
+
+/// Counts a context as active on a member until dropped.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self(active.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl MemberStats {
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // zero if not measured or expired
+    fn rtt(&self, now: Instant) -> Duration {
+        match *self.rtt.lock().unwrap() {
+            Some((rtt, time)) if now.saturating_duration_since(time) < RTT_EXPIRY => rtt,
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn update_rtt(&self, sample: Duration) {
+        let now = Instant::now();
+        let mut rtt = self.rtt.lock().unwrap();
+        let ewma = match *rtt {
+            Some((old, time)) if now - time < RTT_EXPIRY => (old * 7 + sample * 3) / 10,
+            _ => sample,
+        };
+        *rtt = Some((ewma, now));
+    }
+}
+
+// FNV-1a, unlike DefaultHasher its output is fixed, so members picked
+// for a key stay the same across restarts and builds. high bits of FNV
+// hardly change with the last bytes, mixed with the finalizer of MurmurHash3.
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = fnv::FnvHasher::default();
+    for part in parts {
+        hasher.write(part);
+    }
+    let mut h = hasher.finish();
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
 pub enum Algorithm {
     Random,
     #[serde(alias = "rr")]
+    #[default]
     RoundRobin,
     #[serde(alias = "hash")]
     HashBy(String),
-    // TODO: Those Algorithms are not yet ready as i had to find a good way to collect data.
-    // LeastConnection,
-    // LeastRTT,
-}
-
-impl Default for Algorithm {
-    fn default() -> Algorithm {
-        Self::RoundRobin
-    }
+    #[serde(alias = "leastConn")]
+    LeastConnection,
+    #[serde(alias = "leastRTT")]
+    LeastRtt,
 }
 
 pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
+    ConnectOptions::reject(value)?;
     let ret: LoadBalanceConnector =
         serde_yaml::from_value(value.clone()).context("parse config")?;
     Ok(Box::new(ret))
@@ -84,19 +293,47 @@ impl Connector for LoadBalanceConnector {
             );
             self.hash_by = Some(value);
         }
+        if let Some(check) = &self.health_check {
+            ensure!(
+                check.interval > 0 && check.timeout > 0,
+                "health check interval and timeout must greater than zero"
+            );
+            ensure!(
+                check.rise > 0 && check.fall > 0,
+                "health check rise and fall must greater than zero"
+            );
+        }
+        for m in &self.connectors {
+            ensure!(
+                m.weight() > 0,
+                "weight of {} must greater than zero",
+                m.name()
+            );
+        }
+        self.health = self.connectors.iter().map(|_| Default::default()).collect();
+        self.stats = self.connectors.iter().map(|_| Default::default()).collect();
+        *self.rr_weights.get_mut().unwrap() = vec![0; self.connectors.len()];
+        #[cfg(feature = "metrics")]
+        {
+            self.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        }
         Ok(())
     }
 
-    async fn verify(&self, state: Arc<GlobalState>) -> Result<(), Error> {
+    fn references(&self) -> Vec<&str> {
+        self.connectors.iter().map(Member::name).collect()
+    }
+
+    async fn verify(&self, connectors: &HashMap<String, Arc<dyn Connector>>) -> Result<(), Error> {
         ensure!(!self.connectors.is_empty(), "connectors must not be empty");
-        for n in &self.connectors {
+        for m in &self.connectors {
             ensure!(
-                state.connectors.contains_key(n),
+                connectors.contains_key(m.name()),
                 "connector not defined: {}",
-                n
+                m.name()
             );
         }
-        Ok(())
+        super::check_loops(&self.name, connectors)
     }
 
     async fn connect(
@@ -104,46 +341,389 @@ impl Connector for LoadBalanceConnector {
         state: Arc<GlobalState>,
         ctx: ContextRef,
     ) -> Result<(), Error> {
-        let conn = match self.algorithm {
-            Algorithm::RoundRobin => self.round_robin(&state)?,
-            Algorithm::Random => self.random(&state)?,
-            Algorithm::HashBy(_) => self.hash_by(&state, &ctx).await?,
+        let candidates = self.candidates();
+        let idx = match self.algorithm {
+            Algorithm::RoundRobin => self.round_robin(&candidates),
+            Algorithm::Random => self.random(&candidates),
+            Algorithm::HashBy(_) => self.hash_by(&candidates, &ctx).await?,
+            Algorithm::LeastConnection => self.least_connection(&candidates),
+            Algorithm::LeastRtt => self.least_rtt(&candidates),
         };
+        let conn = self.member(&state, self.connectors[idx].name()).await?;
         let next = conn.name().to_owned();
         debug!("{}: selected connector: {}", self.name, next);
         ctx.write().await.set_connector(next);
-        conn.connect(state, ctx).await
+
+        let stats = &self.stats[idx];
+        // counted before connecting, so that concurrent connects spread over members
+        let guard = match self.algorithm {
+            Algorithm::LeastConnection => Some(ActiveGuard::new(&stats.active)),
+            _ => None,
+        };
+        match super::connect_timed(conn, state, ctx.clone()).await {
+            Ok(rtt) => {
+                stats.update_rtt(rtt);
+                if let Some(guard) = guard {
+                    ctx.write().await.hold(guard);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                stats.update_rtt(RTT_FAILURE_PENALTY);
+                Err(e)
+            }
+        }
+    }
+
+    fn start(self: Arc<Self>, state: Arc<GlobalState>) {
+        let interval = match &self.health_check {
+            Some(check) => Duration::from_secs(check.interval),
+            None => return,
+        };
+        #[cfg(feature = "metrics")]
+        self.publish_health();
+        // holds a weak reference only, so the task ends after the
+        // connector is removed by a config reload
+        let this = Arc::downgrade(&self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match this.upgrade() {
+                    Some(this) => this.check_health(&state).await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    fn health(&self) -> Option<HashMap<String, bool>> {
+        self.health_check.as_ref()?;
+        Some(
+            self.connectors
+                .iter()
+                .map(|m| m.name().to_owned())
+                .zip(self.health.iter().map(MemberHealth::is_healthy))
+                .collect(),
+        )
     }
 }
 
 impl LoadBalanceConnector {
-    fn random(self: &Arc<Self>, state: &Arc<GlobalState>) -> Result<Arc<dyn Connector>, Error> {
-        let next = self.connectors.choose(&mut thread_rng()).unwrap();
-        Ok(state.connectors.get(next).unwrap().clone())
+    fn weight(&self, idx: usize) -> u64 {
+        self.connectors[idx].weight() as u64
     }
 
-    fn round_robin(
-        self: &Arc<Self>,
-        state: &Arc<GlobalState>,
-    ) -> Result<Arc<dyn Connector>, Error> {
-        let next = self.idx.fetch_add(1, Ordering::Relaxed);
-        let next = &self.connectors[next % self.connectors.len()];
-        Ok(state.connectors.get(next).unwrap().clone())
+    fn random(&self, candidates: &[usize]) -> usize {
+        *candidates
+            .choose_weighted(&mut thread_rng(), |&i| self.weight(i))
+            .unwrap()
     }
 
-    async fn hash_by(
-        self: &Arc<Self>,
-        state: &Arc<GlobalState>,
-        ctx: &ContextRef,
-    ) -> Result<Arc<dyn Connector>, Error> {
+    // smooth weighted round robin, spreads picks of heavier members evenly
+    fn round_robin(&self, candidates: &[usize]) -> usize {
+        let mut current = self.rr_weights.lock().unwrap();
+        let total: i64 = candidates.iter().map(|&i| self.weight(i) as i64).sum();
+        let mut best = candidates[0];
+        for &i in candidates {
+            current[i] += self.weight(i) as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    async fn hash_by(&self, candidates: &[usize], ctx: &ContextRef) -> Result<usize, Error> {
         let ctx = create_context(ctx.read().await.props().clone());
         let result = self.hash_by.as_ref().unwrap().real_value_of(ctx.into())?;
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        use std::hash::Hasher;
-        result.hash(&mut hasher);
-        let hash = hasher.finish() as usize;
-        debug!("result: {:?} hash: {:?}", result, hash);
-        let next = &self.connectors[hash % self.connectors.len()];
-        Ok(state.connectors.get(next).unwrap().clone())
+        let key: String = result.try_into()?;
+        let hash = stable_hash(&[key.as_bytes()]);
+        debug!("result: {:?} hash: {:?}", key, hash);
+        Ok(self.rendezvous(candidates, hash))
+    }
+
+    // weighted rendezvous hashing: every member scores the key on its own,
+    // so adding or removing a member only moves keys from or to that member.
+    fn rendezvous(&self, candidates: &[usize], key: u64) -> usize {
+        let score = |i: usize| {
+            let hash = stable_hash(&[&key.to_le_bytes(), self.connectors[i].name().as_bytes()]);
+            // uniform in (0, 1)
+            let x = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            self.weight(i) as f64 / -x.ln()
+        };
+        *candidates
+            .iter()
+            .max_by(|&&a, &&b| score(a).total_cmp(&score(b)))
+            .unwrap()
+    }
+
+    // lowest active contexts per weight
+    fn least_connection(&self, candidates: &[usize]) -> usize {
+        let active: Vec<(usize, u64)> = candidates
+            .iter()
+            .map(|&i| (i, self.stats[i].active() as u64))
+            .collect();
+        active
+            .iter()
+            .min_by(|(a, x), (b, y)| (x * self.weight(*b)).cmp(&(y * self.weight(*a))))
+            .unwrap()
+            .0
+    }
+
+    // lowest connect latency per weight, unmeasured members come first
+    fn least_rtt(&self, candidates: &[usize]) -> usize {
+        let now = Instant::now();
+        *candidates
+            .iter()
+            .min_by_key(|&&i| self.stats[i].rtt(now).as_micros() / self.weight(i) as u128)
+            .unwrap()
+    }
+
+    // indexes of healthy members, or all of them if none is healthy
+    fn candidates(&self) -> Vec<usize> {
+        let healthy: Vec<usize> = (0..self.connectors.len())
+            .filter(|&i| self.health[i].is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            (0..self.connectors.len()).collect()
+        } else {
+            healthy
+        }
+    }
+
+    async fn check_health(&self, state: &Arc<GlobalState>) {
+        let check = self.health_check.as_ref().unwrap();
+        let probes = self.connectors.iter().map(|m| async move {
+            let name = m.name();
+            let start = Instant::now();
+            let ret = self.probe(state, name, check).await;
+            if let Err(e) = &ret {
+                debug!("{}: health probe of {} failed: {}", self.name, name, e);
+            }
+            ret.map(|_| start.elapsed())
+        });
+        let results = futures::future::join_all(probes).await;
+        for (i, ret) in results.into_iter().enumerate() {
+            let (name, health) = (self.connectors[i].name(), &self.health[i]);
+            // probes keep latency of idle or failed members up to date
+            self.stats[i].update_rtt(*ret.as_ref().unwrap_or(&RTT_FAILURE_PENALTY));
+            match health.report(ret.is_ok(), check) {
+                Some(true) => info!("{}: connector {} is healthy", self.name, name),
+                Some(false) => warn!("{}: connector {} is unhealthy", self.name, name),
+                None => {}
+            }
+            #[cfg(feature = "metrics")]
+            self.set_health(name, health.is_healthy());
+        }
+    }
+
+    async fn probe(
+        &self,
+        state: &Arc<GlobalState>,
+        name: &str,
+        check: &HealthCheck,
+    ) -> Result<(), Error> {
+        let conn = self.member(state, name).await?;
+        let source = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let ctx = state
+            .contexts
+            .create_detached_context(self.name.to_owned(), source);
+        ctx.write()
+            .await
+            .set_target(check.target.clone())
+            .set_feature(Feature::TcpForward);
+        tokio::time::timeout(
+            Duration::from_secs(check.timeout),
+            conn.connect(state.clone(), ctx),
+        )
+        .await
+        .unwrap_or_else(|_| Err(err_msg("probe timeout")))
+    }
+
+    // members are looked up on every connect, so that a config reload
+    // replacing them takes effect without touching this connector.
+    async fn member(&self, state: &GlobalState, name: &str) -> Result<Arc<dyn Connector>, Error> {
+        state
+            .connectors
+            .read()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or_else(|| err_msg(format!("connector not defined: {}", name)))
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl LoadBalanceConnector {
+    // replaces series of the previous instance with ones of this instance
+    fn publish_health(&self) {
+        let mut owners = HEALTH_OWNERS.lock().unwrap();
+        if let Some((_, members)) = owners.remove(&self.name) {
+            remove_health(&self.name, &members);
+        }
+        let members: Vec<String> = self.connectors.iter().map(|m| m.name().into()).collect();
+        for m in &members {
+            MEMBER_HEALTH.with_label_values(&[&self.name, m]).set(1);
+        }
+        owners.insert(self.name.clone(), (self.id, members));
+    }
+
+    fn set_health(&self, member: &str, healthy: bool) {
+        let owners = HEALTH_OWNERS.lock().unwrap();
+        if matches!(owners.get(&self.name), Some((id, _)) if *id == self.id) {
+            MEMBER_HEALTH
+                .with_label_values(&[&self.name, member])
+                .set(healthy as i64);
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn remove_health(name: &str, members: &[String]) {
+    for m in members {
+        MEMBER_HEALTH.remove_label_values(&[name, m]).ok();
+    }
+}
+
+// removes series of connectors dropped by a reload, unless taken over
+#[cfg(feature = "metrics")]
+impl Drop for LoadBalanceConnector {
+    fn drop(&mut self) {
+        let mut owners = HEALTH_OWNERS.lock().unwrap();
+        if matches!(owners.get(&self.name), Some((id, _)) if *id == self.id) {
+            let (_, members) = owners.remove(&self.name).unwrap();
+            remove_health(&self.name, &members);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_rise_and_fall() {
+        let check: HealthCheck =
+            serde_yaml::from_str("{target: 127.0.0.1:80, rise: 2, fall: 3}").unwrap();
+        let health = MemberHealth::default();
+        assert_eq!(health.report(false, &check), None);
+        assert_eq!(health.report(false, &check), None);
+        assert_eq!(health.report(false, &check), Some(false));
+        assert!(!health.is_healthy());
+        assert_eq!(health.report(true, &check), None);
+        assert_eq!(health.report(false, &check), None);
+        assert_eq!(health.report(true, &check), None);
+        assert_eq!(health.report(true, &check), Some(true));
+        assert!(health.is_healthy());
+    }
+
+    async fn create(s: &str) -> LoadBalanceConnector {
+        let mut ret: LoadBalanceConnector = serde_yaml::from_str(s).unwrap();
+        ret.init().await.unwrap();
+        ret
+    }
+
+    #[tokio::test]
+    async fn weighted_round_robin() {
+        let lb = create("{name: lb, connectors: [{name: a, weight: 2}, b]}").await;
+        let picks: Vec<&str> = (0..6)
+            .map(|_| lb.connectors[lb.round_robin(&lb.candidates())].name())
+            .collect();
+        assert_eq!(picks, vec!["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[tokio::test]
+    async fn rendezvous() {
+        let lb = create("{name: lb, connectors: [a, b, c], algo: {hashBy: request.source}}").await;
+        let keys = 0..10000u64;
+        let select = |candidates: &[usize]| -> Vec<usize> {
+            keys.clone().map(|k| lb.rendezvous(candidates, k)).collect()
+        };
+        let all = select(&[0, 1, 2]);
+        // only keys of the removed member move
+        let without_b = select(&[0, 2]);
+        for (x, y) in all.iter().zip(&without_b) {
+            assert!(*x == 1 || x == y);
+        }
+        let counts = all.iter().fold([0; 3], |mut acc, &i| {
+            acc[i] += 1;
+            acc
+        });
+        assert!(counts.iter().all(|&n| n > 3000 && n < 3700), "{:?}", counts);
+
+        let lb = create(
+            "{name: lb, connectors: [a, {name: b, weight: 3}], algo: {hashBy: request.source}}",
+        )
+        .await;
+        let heavy = keys.filter(|&k| lb.rendezvous(&[0, 1], k) == 1).count();
+        assert!(heavy > 7000 && heavy < 8000, "{}", heavy);
+    }
+
+    #[test]
+    fn stable_hash_value() {
+        // keys must map to the same members on every build
+        assert_eq!(stable_hash(&[b"127.0.0.1"]), 14906417390966373154);
+    }
+
+    #[tokio::test]
+    async fn least_rtt() {
+        let lb = create("{name: lb, connectors: [a, b, c], algo: leastRtt}").await;
+        lb.stats[0].update_rtt(Duration::from_millis(10));
+        lb.stats[2].update_rtt(Duration::from_millis(5));
+        // b is not measured yet
+        assert_eq!(lb.least_rtt(&lb.candidates()), 1);
+        lb.stats[1].update_rtt(Duration::from_millis(20));
+        assert_eq!(lb.least_rtt(&lb.candidates()), 2);
+        lb.stats[2].update_rtt(RTT_FAILURE_PENALTY);
+        assert_eq!(lb.least_rtt(&lb.candidates()), 0);
+        // failed members are tried again once their latency expires
+        let later = Instant::now() + RTT_EXPIRY;
+        assert_eq!(lb.stats[2].rtt(later), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn least_connection() {
+        let lb = create("{name: lb, connectors: [a, {name: b, weight: 2}], algo: leastConn}").await;
+        let mut guards = vec![];
+        let picks: Vec<usize> = (0..6)
+            .map(|_| {
+                let idx = lb.least_connection(&lb.candidates());
+                guards.push(ActiveGuard::new(&lb.stats[idx].active));
+                idx
+            })
+            .collect();
+        assert_eq!(picks, vec![0, 1, 1, 0, 1, 1]);
+        guards.truncate(3);
+        assert_eq!(lb.stats[0].active(), 1);
+        assert_eq!(lb.stats[1].active(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn health_series() {
+        use prometheus::core::Collector;
+        let members = || -> Vec<String> {
+            let mut ret: Vec<String> = MEMBER_HEALTH.collect()[0]
+                .get_metric()
+                .iter()
+                .filter(|m| m.get_label()[0].get_value() == "series")
+                .map(|m| m.get_label()[1].get_value().to_owned())
+                .collect();
+            ret.sort();
+            ret
+        };
+        let old = create("{name: series, connectors: [a, b]}").await;
+        old.publish_health();
+        assert_eq!(members(), vec!["a", "b"]);
+        // reloaded with b replaced by c, while the old one is still alive
+        let new = create("{name: series, connectors: [a, c]}").await;
+        new.publish_health();
+        old.set_health("b", false);
+        drop(old);
+        assert_eq!(members(), vec!["a", "c"]);
+        drop(new);
+        assert!(members().is_empty());
     }
 }
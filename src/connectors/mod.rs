@@ -1,25 +1,40 @@
 use crate::{
-    context::{ContextRef, Feature},
+    context::{ContextRef, Feature, IOBufStream, TargetAddress},
     GlobalState,
 };
 use async_trait::async_trait;
-use easy_error::{bail, err_msg, Error};
+use easy_error::{bail, ensure, err_msg, Error};
 use serde_yaml::Value;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
 
+mod chain;
 mod direct;
+mod fallback;
 mod http;
 mod loadbalance;
+mod options;
 #[cfg(feature = "quic")]
 mod quic;
 mod socks;
+#[cfg(unix)]
+mod unix;
+
+pub use options::ConnectOptions;
 
 #[async_trait]
 pub trait Connector: Send + Sync {
     async fn init(&mut self) -> Result<(), Error> {
         Ok(())
     }
-    async fn verify(&self, _state: Arc<GlobalState>) -> Result<(), Error> {
+    /// Checks references to other connectors, `connectors` is the set that
+    /// will be active once this connector is in use.
+    async fn verify(&self, _connectors: &HashMap<String, Arc<dyn Connector>>) -> Result<(), Error> {
         Ok(())
     }
     async fn connect(
@@ -27,16 +42,143 @@ pub trait Connector: Send + Sync {
         state: Arc<GlobalState>,
         ctx: ContextRef,
     ) -> Result<(), Error>;
+    /// Like connect, but performs the handshake over `server`, an already
+    /// established transport to this connector's server.
+    /// `local` and `remote` are the addresses of the underlying connection.
+    async fn connect_over(
+        self: Arc<Self>,
+        _state: Arc<GlobalState>,
+        _ctx: ContextRef,
+        _server: IOBufStream,
+        _local: SocketAddr,
+        _remote: SocketAddr,
+    ) -> Result<(), Error> {
+        bail!("connector {} can not be chained", self.name())
+    }
+    /// Address of the upstream server, connectors returning None here
+    /// do not support connect_over.
+    fn server_address(&self) -> Option<TargetAddress> {
+        None
+    }
     fn name(&self) -> &str;
+    /// Timeouts and retries applied by `connect`, connectors delegating to
+    /// other connectors keep the default and leave them to members.
+    fn options(&self) -> &ConnectOptions {
+        &ConnectOptions::DEFAULT
+    }
     fn features(&self) -> &[Feature] {
         &[Feature::TcpForward]
     }
     fn has_feature(&self, feature: Feature) -> bool {
         self.features().contains(&feature)
     }
+    /// Names of connectors this one passes connections to.
+    fn references(&self) -> Vec<&str> {
+        Vec::new()
+    }
+    /// Starts background tasks, called once the connector is put in use.
+    fn start(self: Arc<Self>, _state: Arc<GlobalState>) {}
+    /// Health of member connectors, for connectors that check them.
+    fn health(&self) -> Option<HashMap<String, bool>> {
+        None
+    }
 }
 
 pub type ConnectorRef = Box<dyn Connector>;
+
+/// Connects `ctx` with `conn`, failed attempts are retried as configured
+/// by the connector's options.
+pub async fn connect(
+    conn: Arc<dyn Connector>,
+    state: Arc<GlobalState>,
+    ctx: ContextRef,
+) -> Result<(), Error> {
+    connect_timed(conn, state, ctx).await.map(|_| ())
+}
+
+/// Like `connect`, returns the time taken by the successful attempt,
+/// failed attempts and delays between them are not counted.
+pub async fn connect_timed(
+    conn: Arc<dyn Connector>,
+    state: Arc<GlobalState>,
+    ctx: ContextRef,
+) -> Result<Duration, Error> {
+    let mut n = 0;
+    loop {
+        let start = Instant::now();
+        let ret = conn.clone().connect(state.clone(), ctx.clone()).await;
+        let opts = conn.options();
+        match ret {
+            Ok(()) => return Ok(start.elapsed()),
+            Err(e) if n < opts.retries => {
+                // the client has been told where to connect, too late to retry
+                if ctx.read().await.extra("tcp-bind-address").is_some() {
+                    return Err(e);
+                }
+                let delay = opts.retry_delay(n);
+                warn!(
+                    "{}: connect failed: {} cause: {:?}, retry in {:?}",
+                    conn.name(),
+                    e,
+                    e.cause,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                n += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Fails if connections passed on by connector `name` may end up
+/// going around in circles.
+pub fn check_loops(
+    name: &str,
+    connectors: &HashMap<String, Arc<dyn Connector>>,
+) -> Result<(), Error> {
+    fn visit<'a>(
+        path: &mut Vec<&'a str>,
+        connectors: &'a HashMap<String, Arc<dyn Connector>>,
+    ) -> Result<(), Error> {
+        let refs = match connectors.get(*path.last().unwrap()) {
+            Some(c) => c.references(),
+            None => return Ok(()),
+        };
+        for next in refs {
+            let looped = path.contains(&next);
+            path.push(next);
+            ensure!(!looped, "connector loop: {}", path.join(" -> "));
+            visit(path, connectors)?;
+            path.pop();
+        }
+        Ok(())
+    }
+    visit(&mut vec![name], connectors)
+}
+
+/// Global state with `connectors` parsed, initialized and verified.
+#[cfg(test)]
+pub(crate) async fn test_state(connectors: &str) -> Result<Arc<GlobalState>, Error> {
+    let mut connectors = from_config(&serde_yaml::from_str::<Vec<Value>>(connectors).unwrap())?;
+    for c in connectors.values_mut() {
+        Arc::get_mut(c).unwrap().init().await?;
+    }
+    for c in connectors.values() {
+        c.verify(&connectors).await?;
+    }
+    let mut state = GlobalState::default();
+    *state.connectors.get_mut() = connectors;
+    Ok(Arc::new(state))
+}
+
+fn server_address(server: &str, port: u16) -> TargetAddress {
+    match server.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).into(),
+        Err(_) => (server.to_owned(), port).into(),
+    }
+}
+
 pub fn from_config(cfg: &[Value]) -> Result<HashMap<String, Arc<dyn Connector>>, Error> {
     let mut ret: HashMap<String, Arc<dyn Connector>> = Default::default();
     for val in cfg {
@@ -62,8 +204,12 @@ pub fn from_value(value: &Value) -> Result<ConnectorRef, Error> {
         "http" => http::from_value(value),
         "socks" => socks::from_value(value),
         "loadbalance" => loadbalance::from_value(value),
+        "fallback" => fallback::from_value(value),
+        "chain" => chain::from_value(value),
         #[cfg(feature = "quic")]
         "quic" => quic::from_value(value),
+        #[cfg(unix)]
+        "unix" => unix::from_value(value),
 
         name => bail!("unknown connector type: {:?}", name),
     }
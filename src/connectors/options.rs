@@ -0,0 +1,149 @@
+use std::{future::Future, time::Duration};
+
+use easy_error::{ensure, err_msg, Error};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "metrics")]
+lazy_static::lazy_static! {
+    static ref CONNECTOR_TIMEOUTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "connector_timeouts",
+        "Number of connect or handshake timeouts of connectors.",
+        &["connector", "phase"]
+    )
+    .unwrap();
+}
+
+/// Timeouts and retry policy shared by all connectors.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectOptions {
+    // deadline of establishing the connection to upstream, unit: seconds
+    pub connect_timeout: Option<u64>,
+    // deadline of tls and protocol handshake with upstream, unit: seconds
+    pub handshake_timeout: Option<u64>,
+    // number of extra attempts after a failed one
+    #[serde(default)]
+    pub retries: u32,
+    // delay before the first retry, doubled for each of the following ones, unit: seconds
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+}
+
+const fn default_retry_delay() -> u64 {
+    1
+}
+
+// keys of ConnectOptions in config
+const KEYS: &[&str] = &[
+    "connectTimeout",
+    "handshakeTimeout",
+    "retries",
+    "retryDelay",
+];
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl ConnectOptions {
+    pub const DEFAULT: Self = Self {
+        connect_timeout: None,
+        handshake_timeout: None,
+        retries: 0,
+        retry_delay: default_retry_delay(),
+    };
+
+    /// Fails if any option is set in `value`, used by connectors which only
+    /// pass connections to other connectors, and leave options to them.
+    pub fn reject(value: &serde_yaml::Value) -> Result<(), Error> {
+        for key in KEYS {
+            ensure!(
+                value.get(key).is_none(),
+                "{} is not supported on this connector, set it on its members",
+                key
+            );
+        }
+        Ok(())
+    }
+
+    pub fn init(&self) -> Result<(), Error> {
+        ensure!(
+            self.connect_timeout != Some(0),
+            "connectTimeout must greater than zero"
+        );
+        ensure!(
+            self.handshake_timeout != Some(0),
+            "handshakeTimeout must greater than zero"
+        );
+        Ok(())
+    }
+
+    /// Runs `f` under connectTimeout.
+    pub async fn connect<T, F>(&self, name: &str, f: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        deadline(name, "connect", self.connect_timeout, f).await
+    }
+
+    /// Runs `f` under handshakeTimeout.
+    pub async fn handshake<T, F>(&self, name: &str, f: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        deadline(name, "handshake", self.handshake_timeout, f).await
+    }
+
+    /// Delay before retry number `n`, counting from zero.
+    pub fn retry_delay(&self, n: u32) -> Duration {
+        Duration::from_secs(self.retry_delay.saturating_mul(1 << n.min(16)))
+    }
+}
+
+async fn deadline<T, F>(name: &str, phase: &str, timeout: Option<u64>, f: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return f.await,
+    };
+    match tokio::time::timeout(Duration::from_secs(timeout), f).await {
+        Ok(ret) => ret,
+        Err(_) => {
+            #[cfg(feature = "metrics")]
+            CONNECTOR_TIMEOUTS.with_label_values(&[name, phase]).inc();
+            #[cfg(not(feature = "metrics"))]
+            let _ = name;
+            Err(err_msg(format!("{} timeout after {}s", phase, timeout)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts() {
+        let opts: ConnectOptions = serde_yaml::from_str("{connectTimeout: 1, retries: 2}").unwrap();
+        let ret: Result<(), _> = opts
+            .connect("test", std::future::pending::<Result<(), Error>>())
+            .await;
+        assert_eq!(ret.unwrap_err().ctx, "connect timeout after 1s");
+        // no handshake timeout configured
+        assert_eq!(opts.handshake("test", async { Ok(1) }).await.unwrap(), 1);
+        assert_eq!(opts.retry_delay(0), Duration::from_secs(1));
+        assert_eq!(opts.retry_delay(2), Duration::from_secs(4));
+        assert_eq!(
+            ConnectOptions::default().retry_delay(0),
+            opts.retry_delay(0)
+        );
+
+        assert!(ConnectOptions::reject(&serde_yaml::from_str("{timeout: 1}").unwrap()).is_ok());
+        let value = serde_yaml::from_str("{retries: 1}").unwrap();
+        assert!(ConnectOptions::reject(&value).is_err());
+    }
+}
@@ -10,7 +10,7 @@ use std::{
 use tokio::sync::Mutex;
 use tracing::debug;
 
-use super::ConnectorRef;
+use super::{http::HttpAuthData, ConnectOptions, ConnectorRef};
 use crate::{
     common::{
         h11c::h11c_connect,
@@ -30,6 +30,8 @@ type QuicConn = (Connection, QuicFrameSessions);
 #[serde(rename_all = "camelCase")]
 pub struct QuicConnector {
     name: String,
+    #[serde(flatten)]
+    options: ConnectOptions,
     server: String,
     port: u16,
     tls: TlsClientConfig,
@@ -39,6 +41,7 @@ pub struct QuicConnector {
     bbr: bool,
     #[serde(default = "default_inline_udp")]
     inline_udp: bool,
+    auth: Option<HttpAuthData>,
     #[serde(skip)]
     endpoint: Option<Endpoint>,
     #[serde(skip)]
@@ -68,11 +71,16 @@ impl super::Connector for QuicConnector {
         self.name.as_str()
     }
 
+    fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
     fn features(&self) -> &[Feature] {
         &[Feature::TcpForward, Feature::UdpForward, Feature::UdpBind]
     }
 
     async fn init(&mut self) -> Result<(), Error> {
+        self.options.init()?;
         self.tls.init()?;
         let cfg = create_quic_client(&self.tls, self.bbr)?;
         let bind = self.bind.parse().context("parse bind")?;
@@ -87,7 +95,10 @@ impl super::Connector for QuicConnector {
         _state: Arc<GlobalState>,
         ctx: ContextRef,
     ) -> Result<(), Error> {
-        let (conn, sessions) = self.get_connection().await?;
+        let (conn, sessions) = self
+            .options
+            .connect(&self.name, self.get_connection())
+            .await?;
         let remote = conn.remote_address();
         let local = self
             .endpoint
@@ -95,10 +106,10 @@ impl super::Connector for QuicConnector {
             .unwrap()
             .local_addr()
             .context("local_addr")?;
-        let ret = self
+        let handshake = self
             .clone()
-            .handshake(conn, sessions, ctx.clone(), remote, local)
-            .await;
+            .handshake(conn, sessions, ctx.clone(), remote, local);
+        let ret = self.options.handshake(&self.name, handshake).await;
         match ret {
             Ok(()) => Ok(()),
             Err(e) => {
@@ -133,7 +144,8 @@ impl QuicConnector {
             "quic-datagrams"
         };
         let frames = |id| create_quic_frames(conn, id, sessions);
-        h11c_connect(server, ctx, local, remote, channel, frames).await?;
+        let auth = self.auth.as_ref().map(HttpAuthData::credentials);
+        h11c_connect(server, ctx, local, remote, auth, channel, frames).await?;
         Ok(())
     }
 
@@ -11,21 +11,25 @@ use crate::{
     common::{
         into_unspecified, set_keepalive,
         socks::{
-            frames::setup_udp_session, PasswordAuth, SocksRequest, SocksResponse,
+            frames::setup_udp_session, PasswordAuth, SocksRequest, SocksResponse, SOCKS_CMD_BIND,
             SOCKS_CMD_CONNECT, SOCKS_CMD_UDP_ASSOCIATE, SOCKS_REPLY_OK,
         },
         tls::TlsClientConfig,
     },
-    context::{make_buffered_stream, ContextRef, Feature},
+    context::{
+        make_buffered_stream, ContextRef, ContextRefOps, Feature, IOBufStream, TargetAddress,
+    },
     GlobalState,
 };
 
-use super::ConnectorRef;
+use super::{ConnectOptions, ConnectorRef};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SocksConnector {
     name: String,
+    #[serde(flatten)]
+    options: ConnectOptions,
     server: String,
     port: u16,
     #[serde(default = "default_socks_version")]
@@ -55,11 +59,21 @@ impl super::Connector for SocksConnector {
         self.name.as_str()
     }
 
+    fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
     fn features(&self) -> &[Feature] {
-        &[Feature::TcpForward, Feature::UdpForward, Feature::UdpBind]
+        &[
+            Feature::TcpForward,
+            Feature::TcpBind,
+            Feature::UdpForward,
+            Feature::UdpBind,
+        ]
     }
 
     async fn init(&mut self) -> Result<(), Error> {
+        self.options.init()?;
         if let Some(Err(e)) = self.tls.as_mut().map(TlsClientConfig::init) {
             return Err(e);
         }
@@ -69,26 +83,99 @@ impl super::Connector for SocksConnector {
         Ok(())
     }
 
+    fn server_address(&self) -> Option<TargetAddress> {
+        Some(super::server_address(&self.server, self.port))
+    }
+
     async fn connect(
         self: Arc<Self>,
-        _state: Arc<GlobalState>,
+        state: Arc<GlobalState>,
         ctx: ContextRef,
     ) -> Result<(), Error> {
-        let tls_insecure = self.tls.as_ref().map(|x| x.insecure).unwrap_or(false);
-        let tls_connector = self.tls.as_ref().map(|options| options.connector());
         trace!(
             "{} connecting to server {}:{}",
             self.name,
             self.server,
             self.port
         );
-        let server = TcpStream::connect((self.server.as_str(), self.port))
-            .await
-            .with_context(|| format!("failed to connect to upstream server: {}", self.server))?;
+        let server = self
+            .options
+            .connect(&self.name, async {
+                TcpStream::connect((self.server.as_str(), self.port))
+                    .await
+                    .with_context(|| {
+                        format!("failed to connect to upstream server: {}", self.server)
+                    })
+            })
+            .await?;
         let local = server.local_addr().context("local_addr")?;
         let remote = server.peer_addr().context("peer_addr")?;
         set_keepalive(&server)?;
+        let server = make_buffered_stream(server);
+        self.connect_over(state, ctx, server, local, remote).await
+    }
+
+    async fn connect_over(
+        self: Arc<Self>,
+        _state: Arc<GlobalState>,
+        ctx: ContextRef,
+        server: IOBufStream,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(), Error> {
+        let (mut server, resp) = self
+            .options
+            .handshake(&self.name, self.handshake(&ctx, server))
+            .await?;
+        let feature = ctx.read().await.feature();
+        if feature == Feature::TcpBind {
+            // first reply carries the address upstream server is listening on,
+            // the second one is sent once the peer has connected.
+            let listen_addr = match resp.target.as_socket_addr() {
+                Some(addr) if addr.ip().is_unspecified() => {
+                    SocketAddr::new(remote.ip(), addr.port()).into()
+                }
+                _ => resp.target.clone(),
+            };
+            ctx.on_bind_listen(listen_addr).await;
+            let resp = SocksResponse::read_from(&mut server).await?;
+            if resp.cmd != SOCKS_REPLY_OK {
+                bail!("upstream server failure: {:?}", resp.cmd);
+            }
+            ctx.write().await.set_extra("tcp-bind-peer", resp.target);
+        }
+        ctx.write()
+            .await
+            .set_server_stream(server)
+            .set_local_addr(local)
+            .set_server_addr(remote);
+        if feature == Feature::UdpBind || feature == Feature::UdpForward {
+            let mut udp_remote = resp
+                .target
+                .as_socket_addr()
+                .ok_or_else(|| err_msg("bad bind address"))?;
+            if udp_remote.ip().is_unspecified() {
+                udp_remote = SocketAddr::new(remote.ip(), udp_remote.port());
+            }
+            let udp_local = into_unspecified(remote);
+            let (_, frames) = setup_udp_session(udp_local, Some(udp_remote))
+                .await
+                .context("setup_udp_session")?;
+            ctx.write().await.set_server_frames(frames);
+        }
+        Ok(())
+    }
+}
 
+impl SocksConnector {
+    // tls handshake and the first request/response of socks protocol
+    async fn handshake(
+        &self,
+        ctx: &ContextRef,
+        server: IOBufStream,
+    ) -> Result<(IOBufStream, SocksResponse), Error> {
+        let tls_insecure = self.tls.as_ref().map(|x| x.insecure).unwrap_or(false);
+        let tls_connector = self.tls.as_ref().map(|options| options.connector());
         let mut server = if let Some(connector) = tls_connector {
             let domain = ServerName::try_from(self.server.as_str())
                 .or_else(|e| {
@@ -106,13 +193,12 @@ impl super::Connector for SocksConnector {
                     .context("tls connector error")?,
             )
         } else {
-            make_buffered_stream(server)
+            server
         };
-        let feature = ctx.read().await.feature();
-        let cmd = match feature {
+        let cmd = match ctx.read().await.feature() {
             Feature::UdpBind | Feature::UdpForward => SOCKS_CMD_UDP_ASSOCIATE,
             Feature::TcpForward => SOCKS_CMD_CONNECT,
-            _ => bail!("unknown supported feature: {:?}", feature),
+            Feature::TcpBind => SOCKS_CMD_BIND,
         };
         let auth = self
             .auth
@@ -129,25 +215,6 @@ impl super::Connector for SocksConnector {
         if resp.cmd != SOCKS_REPLY_OK {
             bail!("upstream server failure: {:?}", resp.cmd);
         }
-        ctx.write()
-            .await
-            .set_server_stream(server)
-            .set_local_addr(local)
-            .set_server_addr(remote);
-        if feature == Feature::UdpBind || feature == Feature::UdpForward {
-            let mut udp_remote = resp
-                .target
-                .as_socket_addr()
-                .ok_or_else(|| err_msg("bad bind address"))?;
-            if udp_remote.ip().is_unspecified() {
-                udp_remote = SocketAddr::new(remote.ip(), udp_remote.port());
-            }
-            let udp_local = into_unspecified(remote);
-            let (_, frames) = setup_udp_session(udp_local, Some(udp_remote))
-                .await
-                .context("setup_udp_session")?;
-            ctx.write().await.set_server_frames(frames);
-        }
-        Ok(())
+        Ok((server, resp))
     }
 }
@@ -0,0 +1,70 @@
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use easy_error::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixStream;
+use tracing::debug;
+
+use super::{ConnectOptions, Connector, ConnectorRef};
+use crate::{
+    context::{make_buffered_stream, ContextRef},
+    GlobalState,
+};
+
+/// Forwards every connection to a unix domain socket, whatever the target is.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UnixConnector {
+    name: String,
+    #[serde(flatten)]
+    options: ConnectOptions,
+    path: PathBuf,
+}
+
+pub fn from_value(value: &serde_yaml::Value) -> Result<ConnectorRef, Error> {
+    let ret: UnixConnector = serde_yaml::from_value(value.clone()).context("parse config")?;
+    Ok(Box::new(ret))
+}
+
+#[async_trait]
+impl Connector for UnixConnector {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+
+    async fn init(&mut self) -> Result<(), Error> {
+        self.options.init()
+    }
+
+    async fn connect(
+        self: Arc<Self>,
+        _state: Arc<GlobalState>,
+        ctx: ContextRef,
+    ) -> Result<(), Error> {
+        let target = ctx.read().await.target();
+        debug!(
+            "{}: connecting {} via {}",
+            self.name,
+            target,
+            self.path.display()
+        );
+        let server = self
+            .options
+            .connect(&self.name, async {
+                UnixStream::connect(&self.path)
+                    .await
+                    .with_context(|| format!("connect to {}", self.path.display()))
+            })
+            .await?;
+        // there are no addresses to report, local and server address stay unspecified
+        ctx.write()
+            .await
+            .set_server_stream(make_buffered_stream(server));
+        Ok(())
+    }
+}
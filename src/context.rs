@@ -1,6 +1,11 @@
-use crate::{access_log::AccessLog, common::frames::FrameIO};
+use crate::{
+    access_log::AccessLog,
+    common::{frames::FrameIO, proxy_protocol::ProxyHeader, try_map_v4_addr},
+    connection_limits::{ConnectionGuard, ConnectionLimits},
+    quota::Quotas,
+};
 use async_trait::async_trait;
-use easy_error::{Error, ResultExt};
+use easy_error::{err_msg, Error, ResultExt};
 use serde::{de::Visitor, ser::SerializeStruct, Deserialize, Serialize};
 use std::{
     any::Any,
@@ -20,9 +25,10 @@ use std::{
 use tokio::{
     io::{AsyncRead, AsyncWrite, BufReader, BufWriter},
     net::lookup_host,
-    sync::{mpsc::Sender, Mutex, RwLock},
+    sync::{broadcast, mpsc::Sender, Mutex, RwLock},
 };
-use tracing::trace;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace};
 
 #[derive(Debug)]
 pub struct InvalidAddress;
@@ -56,8 +62,7 @@ impl TargetAddress {
             Self::DomainPort(host, port) => {
                 let addr = format!("{}:{}", host, port);
                 let mut ret = lookup_host(addr.as_str()).await?;
-                ret.next()
-                    .ok_or_else(|| IoError::new(std::io::ErrorKind::Other, "DNS Error"))
+                ret.next().ok_or_else(|| IoError::other("DNS Error"))
             }
             Self::SocketAddr(addr) => Ok(*addr),
             _ => unreachable!(),
@@ -239,6 +244,9 @@ pub fn make_buffered_stream<T: IOStream + 'static>(stream: T) -> IOBufStream {
 #[async_trait]
 pub trait ContextCallback: Send + Sync {
     async fn on_connect(&self, _ctx: &mut Context) {}
+    // called by connectors once the listening address of a TcpBind request is known,
+    // before waiting for the incoming connection.
+    async fn on_bind_listen(&self, _ctx: &mut Context, _addr: TargetAddress) {}
     async fn on_error(&self, _ctx: &mut Context, _error: Error) {}
     async fn on_finish(&self, _ctx: &mut Context) {}
 }
@@ -253,6 +261,8 @@ pub enum ContextState {
     ClientShutdown,
     Terminated,
     ErrorOccured,
+    // refused by connection limits
+    Rejected,
 }
 
 impl ContextState {
@@ -267,6 +277,7 @@ impl ContextState {
             Self::ServerShutdown => "ServerShutdown",
             Self::Terminated => "Terminated",
             Self::ErrorOccured => "ErrorOccured",
+            Self::Rejected => "Rejected",
         }
     }
 }
@@ -295,6 +306,38 @@ impl From<(ContextState, SystemTime)> for ContextStateLog {
     }
 }
 
+// events buffered per subscriber before it starts lagging
+const EVENT_CAPACITY: usize = 1024;
+
+/// Lifecycle events of contexts, published to subscribers of `GlobalState`.
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq)]
+pub enum ContextEventKind {
+    Created,
+    StateChanged,
+    ConnectorSelected,
+    // sent on drop with final props
+    Terminated,
+}
+
+impl ContextEventKind {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::StateChanged => "state",
+            Self::ConnectorSelected => "connector",
+            Self::Terminated => "terminated",
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct ContextEvent {
+    pub kind: ContextEventKind,
+    pub props: Arc<ContextProps>,
+}
+
 // this is the value object of Context, chould be used in filter evaluation or stored after Context is terminated, for statistics.
 #[derive(Debug, Clone, Serialize)]
 pub struct ContextProps {
@@ -347,6 +390,28 @@ impl Default for ContextProps {
     }
 }
 
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+impl ContextProps {
+    /// Time the context was created.
+    pub fn start_time(&self) -> SystemTime {
+        self.state
+            .first()
+            .map_or(SystemTime::UNIX_EPOCH, |x| x.time)
+    }
+
+    /// Time of the last state change.
+    pub fn last_update(&self) -> SystemTime {
+        self.state.last().map_or(SystemTime::UNIX_EPOCH, |x| x.time)
+    }
+
+    /// Whether a connector ever connected the context.
+    pub fn connected(&self) -> bool {
+        self.state
+            .iter()
+            .any(|x| x.state == ContextState::Connected)
+    }
+}
+
 impl Display for ContextProps {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -367,6 +432,10 @@ pub struct ContextStatistics {
     read_bytes: AtomicUsize,
     read_frames: AtomicUsize,
     last_read: AtomicU64,
+    // bytes read during the last second of io
+    rate: AtomicUsize,
+    #[serde(skip)]
+    rate_mark: AtomicUsize,
 }
 
 impl Default for ContextStatistics {
@@ -375,11 +444,16 @@ impl Default for ContextStatistics {
             read_bytes: AtomicUsize::new(0),
             read_frames: AtomicUsize::new(0),
             last_read: AtomicU64::new(SystemTime::now().unix_timestamp()),
+            rate: AtomicUsize::new(0),
+            rate_mark: AtomicUsize::new(0),
         }
     }
 }
 
 impl ContextStatistics {
+    pub fn read_bytes(&self) -> usize {
+        self.read_bytes.load(Ordering::Relaxed)
+    }
     pub fn incr_sent_bytes(&self, cnt: usize) {
         self.read_bytes.fetch_add(cnt, Ordering::Relaxed);
         self.last_read
@@ -390,6 +464,15 @@ impl ContextStatistics {
         self.last_read
             .store(SystemTime::now().unix_timestamp(), Ordering::Relaxed)
     }
+    /// Called every second while io is in progress.
+    pub fn update_rate(&self) {
+        let bytes = self.read_bytes.load(Ordering::Relaxed);
+        let mark = self.rate_mark.swap(bytes, Ordering::Relaxed);
+        self.rate.store(bytes - mark, Ordering::Relaxed);
+    }
+    pub fn reset_rate(&self) {
+        self.rate.store(0, Ordering::Relaxed);
+    }
     pub fn is_timeout(&self, timeout: Duration) -> bool {
         if timeout.is_zero() {
             return false;
@@ -441,7 +524,11 @@ pub struct GlobalState {
     // use std Mutex here because Drop is not async
     pub gc_list: StdMutex<Vec<Arc<ContextProps>>>,
     pub access_log: Option<AccessLog>,
+    pub quotas: Option<Arc<Quotas>>,
+    pub connection_limits: Option<Arc<ConnectionLimits>>,
     pub default_timeout: u64,
+    // lifecycle events, only enabled when someone may subscribe
+    pub events: Option<broadcast::Sender<ContextEvent>>,
 }
 
 impl GlobalState {
@@ -449,6 +536,32 @@ impl GlobalState {
         self: &Arc<Self>,
         listener: String,
         source: SocketAddr,
+    ) -> ContextRef {
+        let ret = self.new_context(listener, source, false);
+        let props = ret.read().await.props.clone();
+        self.alive
+            .lock()
+            .await
+            .insert(props.id, Arc::downgrade(&ret));
+        self.emit(ContextEventKind::Created, &props);
+        ret
+    }
+
+    /// Creates a context that never shows up in live list, history or access log,
+    /// used by internal connections like health probes.
+    pub fn create_detached_context(
+        self: &Arc<Self>,
+        listener: String,
+        source: SocketAddr,
+    ) -> ContextRef {
+        self.new_context(listener, source, true)
+    }
+
+    fn new_context(
+        self: &Arc<Self>,
+        listener: String,
+        source: SocketAddr,
+        detached: bool,
     ) -> ContextRef {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         let props = Arc::new(ContextProps {
@@ -459,7 +572,7 @@ impl GlobalState {
             state: vec![(ContextState::ClientConnected, SystemTime::now()).into()],
             ..Default::default()
         });
-        let ret = Arc::new(RwLock::new(Context {
+        Arc::new(RwLock::new(Context {
             props,
             client_stream: None,
             server_stream: None,
@@ -467,44 +580,96 @@ impl GlobalState {
             server_frames: None,
             callback: None,
             state: self.clone(),
-        }));
-        self.alive.lock().await.insert(id, Arc::downgrade(&ret));
-        ret
+            cancel: CancellationToken::new(),
+            cancel_reason: None,
+            detached,
+            connection_guard: None,
+            guards: Vec::new(),
+        }))
     }
 
     pub fn gc_thread(self: Arc<Self>) {
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_secs(1)).await;
-                let mut list = Default::default();
-                std::mem::swap(self.gc_list.lock().unwrap().deref_mut(), &mut list);
-                if !list.is_empty() {
-                    trace!("context gc: {}", list.len());
-                    #[cfg(feature = "metrics")]
-                    let timer = {
-                        CONTEXT_GC_COUNT.inc_by(list.len() as u64);
-                        CONTEXT_GC_TIME.start_timer()
-                    };
-                    if let Some(log) = &self.access_log {
-                        for props in list.iter().cloned() {
-                            log.write(props).await.unwrap();
-                        }
-                    }
-                    let mut terminated = self.terminated.lock().await;
-                    let mut alive = self.alive.lock().await;
-                    for props in list {
-                        alive.remove(&props.id).unwrap();
-                        terminated.push_front(props);
-                    }
-                    while terminated.len() > self.history_size {
-                        terminated.pop_back();
-                    }
-                    #[cfg(feature = "metrics")]
-                    timer.stop_and_record();
-                }
+                self.gc().await;
             }
         });
     }
+
+    /// Moves dropped contexts to history and writes them to access log.
+    pub async fn gc(&self) {
+        // also serializes concurrent gc runs, so access log entries are in
+        // the channel once this returns
+        let mut terminated = self.terminated.lock().await;
+        let mut list = Default::default();
+        std::mem::swap(self.gc_list.lock().unwrap().deref_mut(), &mut list);
+        if list.is_empty() {
+            return;
+        }
+        trace!("context gc: {}", list.len());
+        #[cfg(feature = "metrics")]
+        let timer = {
+            CONTEXT_GC_COUNT.inc_by(list.len() as u64);
+            CONTEXT_GC_TIME.start_timer()
+        };
+        if let Some(log) = &self.access_log {
+            for props in list.iter().cloned() {
+                log.write(props).await.unwrap();
+            }
+        }
+        let mut alive = self.alive.lock().await;
+        for props in list.iter() {
+            alive.remove(&props.id).unwrap();
+        }
+        drop(alive);
+        // after removing from alive, so quota collection never sees them again
+        if let Some(quotas) = &self.quotas {
+            for props in list.iter() {
+                quotas.record(props);
+            }
+        }
+        for props in list {
+            terminated.push_front(props);
+        }
+        while terminated.len() > self.history_size {
+            terminated.pop_back();
+        }
+        #[cfg(feature = "metrics")]
+        timer.stop_and_record();
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn enable_events(&mut self) {
+        self.events = Some(broadcast::channel(EVENT_CAPACITY).0);
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<ContextEvent>> {
+        self.events.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    fn emit(&self, kind: ContextEventKind, props: &Arc<ContextProps>) {
+        if let Some(events) = &self.events {
+            // skip cloning props when nobody listens
+            if events.receiver_count() > 0 {
+                let _ = events.send(ContextEvent {
+                    kind,
+                    props: props.clone(),
+                });
+            }
+        }
+    }
+
+    /// Returns contexts that are not dropped yet.
+    pub async fn alive_contexts(&self) -> Vec<ContextRef> {
+        self.alive
+            .lock()
+            .await
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
 }
 
 pub struct Context {
@@ -515,6 +680,12 @@ pub struct Context {
     server_frames: Option<FrameIO>,
     callback: Option<Arc<dyn ContextCallback + Send + Sync>>,
     state: Arc<GlobalState>,
+    cancel: CancellationToken,
+    cancel_reason: Option<String>,
+    detached: bool,
+    connection_guard: Option<ConnectionGuard>,
+    // released when context is dropped
+    guards: Vec<Box<dyn Any + Send + Sync>>,
 }
 
 pub type ContextRef = Arc<RwLock<Context>>;
@@ -535,6 +706,12 @@ impl Context {
         self.callback = None;
     }
 
+    fn emit(&self, kind: ContextEventKind) {
+        if !self.detached {
+            self.state.emit(kind, &self.props);
+        }
+    }
+
     pub fn set_feature(&mut self, feature: Feature) -> &mut Self {
         Arc::make_mut(&mut self.props).request_feature = feature;
         self
@@ -564,6 +741,10 @@ impl Context {
         self.client_stream.take().unwrap()
     }
 
+    pub fn take_server_stream(&mut self) -> Option<IOBufStream> {
+        self.server_stream.take()
+    }
+
     pub fn take_streams(&mut self) -> Option<(IOBufStream, IOBufStream)> {
         if self.client_stream.is_none() || self.server_stream.is_none() {
             return None;
@@ -627,9 +808,37 @@ impl Context {
         self
     }
 
+    /// Replace the source with the client told by a PROXY protocol header,
+    /// addresses of the proxied connection are kept in extras.
+    pub fn set_proxy_header(&mut self, header: ProxyHeader) -> &mut Self {
+        let props = Arc::make_mut(&mut self.props);
+        let peer = std::mem::replace(&mut props.source, try_map_v4_addr(header.source));
+        props
+            .extra
+            .insert("proxy-protocol-peer".to_owned(), peer.to_string());
+        props.extra.insert(
+            "proxy-protocol-destination".to_owned(),
+            header.destination.to_string(),
+        );
+        self
+    }
+
+    /// Set the local address the client connected to, used as destination
+    /// of PROXY protocol headers sent upstream.
+    pub fn set_listener_addr(&mut self, addr: SocketAddr) -> &mut Self {
+        self.set_extra("listener-address", try_map_v4_addr(addr))
+    }
+
+    /// Keeps `guard` alive until the context is dropped.
+    pub fn hold(&mut self, guard: impl Any + Send + Sync) -> &mut Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
     /// Set the connector name.
     pub fn set_connector(&mut self, connector: String) -> &mut Self {
         Arc::make_mut(&mut self.props).connector = Some(connector);
+        self.emit(ContextEventKind::ConnectorSelected);
         self
     }
 
@@ -672,6 +881,7 @@ impl Context {
             .state
             .push((state, SystemTime::now()).into());
         tracing::debug!("set_state: ctx={} state={:?}", self.props, state);
+        self.emit(ContextEventKind::StateChanged);
         self
     }
 
@@ -688,6 +898,34 @@ impl Context {
         Arc::make_mut(&mut self.props).idle_timeout = timeout;
         self
     }
+
+    /// Get the global state this context was created from.
+    pub fn global_state(&self) -> &Arc<GlobalState> {
+        &self.state
+    }
+
+    /// Aborts connecting and io of this context, `reason` is reported as its error.
+    pub fn cancel(&mut self, reason: impl ToString) -> &mut Self {
+        if !self.cancel.is_cancelled() {
+            self.cancel_reason = Some(reason.to_string());
+            self.cancel.cancel();
+        }
+        self
+    }
+
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Error describing why this context was cancelled.
+    pub fn cancelled_error(&self) -> Error {
+        err_msg(
+            self.cancel_reason
+                .as_deref()
+                .unwrap_or("cancelled")
+                .to_owned(),
+        )
+    }
 }
 
 // a set of opreations that aquires write lock
@@ -695,6 +933,7 @@ impl Context {
 pub trait ContextRefOps {
     async fn enqueue(self, queue: &Sender<ContextRef>) -> Result<(), Error>;
     async fn on_connect(&self);
+    async fn on_bind_listen(&self, addr: TargetAddress);
     async fn on_error(&self, error: Error);
     async fn on_finish(&self);
     async fn to_string(&self) -> String;
@@ -703,6 +942,22 @@ pub trait ContextRefOps {
 #[async_trait]
 impl ContextRefOps for ContextRef {
     async fn enqueue(self, queue: &Sender<ContextRef>) -> Result<(), Error> {
+        let mut inner = self.write().await;
+        if let Some(limits) = inner.state.connection_limits.clone() {
+            match limits.acquire(&inner.props) {
+                Ok(guard) => inner.connection_guard = Some(guard),
+                // callers must stop serving the client, like for any other enqueue error
+                Err(e) => {
+                    info!("rejected: {}: {}", e.ctx, inner);
+                    drop(inner);
+                    let reason = e.ctx.clone();
+                    self.on_error(e).await;
+                    self.write().await.set_state(ContextState::Rejected);
+                    return Err(err_msg(format!("rejected: {}", reason)));
+                }
+            }
+        }
+        drop(inner);
         self.write().await.set_state(ContextState::ClientRequested);
         queue.send(self).await.context("enqueue")
     }
@@ -714,6 +969,13 @@ impl ContextRefOps for ContextRef {
         }
         // self.write().await.clear_callback();
     }
+    async fn on_bind_listen(&self, addr: TargetAddress) {
+        let mut inner = self.write().await;
+        inner.set_extra("tcp-bind-address", &addr);
+        if let Some(cb) = inner.callback.clone() {
+            cb.on_bind_listen(&mut inner, addr).await
+        }
+    }
     async fn on_error(&self, error: Error) {
         let mut inner = self.write().await;
         inner
@@ -739,7 +1001,10 @@ impl ContextRefOps for ContextRef {
 impl Drop for Context {
     fn drop(&mut self) {
         trace!("Context dropped: {}", self);
-        self.state.gc_list.lock().unwrap().push(self.props.clone());
+        if !self.detached {
+            self.state.gc_list.lock().unwrap().push(self.props.clone());
+        }
+        self.emit(ContextEventKind::Terminated);
     }
 }
 
@@ -761,10 +1026,11 @@ impl std::fmt::Debug for Context {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
 #[allow(dead_code)]
 pub enum Feature {
     // 1-to-1 connection
+    #[default]
     TcpForward,
     // 1-to-any listening (one shot only)
     TcpBind,
@@ -775,12 +1041,6 @@ pub enum Feature {
     // maybe we should add tap/tun support in the future
 }
 
-impl Default for Feature {
-    fn default() -> Self {
-        Feature::TcpForward
-    }
-}
-
 impl Display for Feature {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Debug::fmt(self, f)
@@ -803,4 +1063,35 @@ mod tests {
         let b = (0x01020304u32, 100).into();
         assert_eq!(a, b);
     }
+
+    #[tokio::test]
+    async fn events() {
+        let source = "127.0.0.1:1234".parse().unwrap();
+        let mut state = GlobalState::default();
+        // nothing to subscribe unless enabled
+        assert!(state.subscribe().is_none());
+        state.enable_events();
+        let state = Arc::new(state);
+        let mut rx = state.subscribe().unwrap();
+        let ctx = state.create_context("a".into(), source).await;
+        ctx.write()
+            .await
+            .set_state(ContextState::ServerConnecting)
+            .set_connector("direct".into());
+        let id = ctx.read().await.props().id;
+        drop(ctx);
+        drop(state.create_detached_context("probe".into(), source));
+
+        let mut next = || {
+            let event = rx.try_recv().unwrap();
+            assert_eq!(event.props.id, id);
+            event.kind
+        };
+        assert_eq!(next(), ContextEventKind::Created);
+        assert_eq!(next(), ContextEventKind::StateChanged);
+        assert_eq!(next(), ContextEventKind::ConnectorSelected);
+        assert_eq!(next(), ContextEventKind::Terminated);
+        // detached contexts are not published
+        assert!(rx.try_recv().is_err());
+    }
 }
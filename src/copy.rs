@@ -1,4 +1,5 @@
 use crate::{
+    bandwidth::{BandwidthLimits, Limiter},
     common::frames::{FrameReader, FrameWriter},
     config::IoParams,
     context::{ContextRef, ContextState, ContextStatistics, IOBufStream},
@@ -92,6 +93,7 @@ async fn copy_half<S, D>(
     mut src: SrcHalf<S>,
     mut dst: DstHalf<D>,
     stat: Arc<ContextStatistics>,
+    limiter: Limiter,
     #[cfg(feature = "metrics")] counter: prometheus::core::GenericCounter<
         prometheus::core::AtomicU64,
     >,
@@ -100,7 +102,7 @@ where
     S: AsyncRead,
     D: AsyncWrite,
 {
-    let mut sbuf = BytesMut::zeroed(params.buffer_size);
+    let mut sbuf = BytesMut::zeroed(limiter.max_chunk(params.buffer_size));
     let have_stream = src.stream.is_some() && dst.stream.is_some();
     let have_frames = src.frames.is_some() && dst.frames.is_some();
     let have_rawfd = src.rawfd.is_some() && dst.rawfd.is_some();
@@ -108,8 +110,8 @@ where
     trait SpliceFn {
         fn read(&mut self) -> BoxFuture<'_, IoResult<usize>>;
         fn write(&mut self, more: bool) -> BoxFuture<'_, IoResult<usize>>;
+        fn shutdown(&mut self) -> IoResult<()>;
     }
-    type BoxSpliceFn = Box<dyn SpliceFn + Send>;
     struct NullFn;
     impl SpliceFn for NullFn {
         fn read(&mut self) -> BoxFuture<'_, IoResult<usize>> {
@@ -118,6 +120,9 @@ where
         fn write(&mut self, _more: bool) -> BoxFuture<'_, IoResult<usize>> {
             unreachable!()
         }
+        fn shutdown(&mut self) -> IoResult<()> {
+            unreachable!()
+        }
     }
     #[cfg(target_os = "linux")]
     let mut pipe_fn: Box<dyn SpliceFn + Send> = if have_rawfd {
@@ -137,6 +142,18 @@ where
             fn write(&mut self, more: bool) -> BoxFuture<'_, IoResult<usize>> {
                 async_splice(&mut self.pipe.0, &self.dfd, self.bufsz, more).boxed()
             }
+            fn shutdown(&mut self) -> IoResult<()> {
+                use nix::{
+                    errno::Errno,
+                    sys::socket::{shutdown, Shutdown},
+                };
+                use std::os::unix::prelude::AsRawFd;
+                match shutdown(self.dfd.as_raw_fd(), Shutdown::Write) {
+                    // peer has closed the connection already
+                    Ok(()) | Err(Errno::ENOTCONN) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
         }
 
         Box::new(PipeFn {
@@ -155,6 +172,7 @@ where
             ret = async {src.stream.as_mut().unwrap().read(&mut sbuf).await}, if have_stream => {
                 let len = ret.with_context(|| format!("read from {}", src.name))?;
                 if len > 0 {
+                    limiter.consume(len).await;
                     dst.stream.as_mut().unwrap().write_all(&sbuf[..len]).await.with_context(|| format!("write to {}", dst.name))?;
                     dst.stream.as_mut().unwrap().flush().await.with_context(|| format!("flush {} buffer", dst.name))?;
                     stat.incr_sent_bytes(len);
@@ -167,6 +185,7 @@ where
             ret = async {src.frames.as_mut().unwrap().read().await}, if have_frames => {
                 let fbuf = ret.with_context(|| format!("read frame from {}", src.name))?;
                 if let Some(fbuf) = fbuf {
+                    limiter.consume(fbuf.len()).await;
                     let len = dst.frames.as_mut().unwrap().write(fbuf).await.with_context(|| format!("write frame to {}", dst.name))?;
                     stat.incr_sent_bytes(len);
                     stat.incr_sent_frames(1);
@@ -194,16 +213,22 @@ where
         }
     }
 
+    if have_rawfd {
+        pipe_fn
+            .shutdown()
+            .with_context(|| format!("shutdown {}", dst.name))?;
+    }
+
     if let Some(mut s) = dst.stream {
         s.shutdown()
             .await
-            .with_context(|| format!("shutdown {})", dst.name))?;
+            .with_context(|| format!("shutdown {}", dst.name))?;
     }
 
     if let Some(mut s) = dst.frames {
         s.shutdown()
             .await
-            .with_context(|| format!("shutdown frame {})", dst.name))?;
+            .with_context(|| format!("shutdown frame {}", dst.name))?;
     }
 
     Ok(())
@@ -216,13 +241,23 @@ async fn drain_buffers(from: &mut IOBufStream, to: &mut IOBufStream) -> IoResult
     }
     to.flush().await
 }
-pub async fn copy_bidi(ctx: ContextRef, params: &IoParams) -> Result<(), Error> {
+pub async fn copy_bidi(
+    ctx: ContextRef,
+    params: &IoParams,
+    limits: &BandwidthLimits,
+) -> Result<(), Error> {
     let mut ctx_lock = ctx.write().await;
     let idle_timeout = ctx_lock.idle_timeout();
     let streams = ctx_lock.take_streams();
     let frames = ctx_lock.take_frames();
     let client_stat = ctx_lock.props().client_stat.clone();
     let server_stat = ctx_lock.props().server_stat.clone();
+    let cancel = ctx_lock.cancellation();
+    let (up_limiter, down_limiter) = limits.limiters(
+        &ctx_lock.props().listener,
+        ctx_lock.props().connector.as_deref().unwrap_or_default(),
+        ctx_lock.extra("user").unwrap_or_default(),
+    );
     #[cfg(feature = "metrics")]
     let client_label = ctx_lock.props().listener.clone();
     #[cfg(feature = "metrics")]
@@ -246,7 +281,9 @@ pub async fn copy_bidi(ctx: ContextRef, params: &IoParams) -> Result<(), Error>
         let client = client.into_inner().into_inner();
         let server = server.into_inner().into_inner();
 
-        if has_raw_fd(&*client) && has_raw_fd(&*server) && params.use_splice {
+        // splice bypasses userspace, leaving no place to apply limits
+        let limited = !up_limiter.is_empty() || !down_limiter.is_empty();
+        if has_raw_fd(&*client) && has_raw_fd(&*server) && params.use_splice && !limited {
             #[cfg(target_os = "linux")]
             {
                 let craw = into_owned_fd(client);
@@ -280,6 +317,7 @@ pub async fn copy_bidi(ctx: ContextRef, params: &IoParams) -> Result<(), Error>
         csrc,
         sdst,
         client_stat.clone(),
+        up_limiter,
         #[cfg(feature = "metrics")]
         IO_BYTES_CLIENT.with_label_values(&[client_label.as_str()]),
     );
@@ -288,6 +326,7 @@ pub async fn copy_bidi(ctx: ContextRef, params: &IoParams) -> Result<(), Error>
         ssrc,
         cdst,
         server_stat.clone(),
+        down_limiter,
         #[cfg(feature = "metrics")]
         IO_BYTES_SERVER.with_label_values(&[server_label.as_str()]),
     );
@@ -299,21 +338,34 @@ pub async fn copy_bidi(ctx: ContextRef, params: &IoParams) -> Result<(), Error>
     let mut c2s = None;
     let mut s2c = None;
 
-    while c2s.is_none() || s2c.is_none() {
-        tokio::select! {
-            biased;
-            ret = (&mut copy_c2s), if c2s.is_none() => {
-                c2s = Some(ret?);
-                ctx.write().await.set_state(ContextState::ClientShutdown);
-            },
-            ret = (&mut copy_s2c), if s2c.is_none() => {
-                s2c = Some(ret?);
-                ctx.write().await.set_state(ContextState::ServerShutdown);
-            },
-            _ = interval.tick() => if server_stat.is_timeout(idle_timeout) && client_stat.is_timeout(idle_timeout){
-                return Err(err_msg("idle timeout"))
+    let ret = async {
+        while c2s.is_none() || s2c.is_none() {
+            tokio::select! {
+                biased;
+                ret = (&mut copy_c2s), if c2s.is_none() => {
+                    c2s = Some(ret?);
+                    ctx.write().await.set_state(ContextState::ClientShutdown);
+                },
+                ret = (&mut copy_s2c), if s2c.is_none() => {
+                    s2c = Some(ret?);
+                    ctx.write().await.set_state(ContextState::ServerShutdown);
+                },
+                _ = interval.tick() => {
+                    client_stat.update_rate();
+                    server_stat.update_rate();
+                    if server_stat.is_timeout(idle_timeout) && client_stat.is_timeout(idle_timeout) {
+                        return Err(err_msg("idle timeout"))
+                    }
+                },
+                _ = cancel.cancelled() => {
+                    return Err(ctx.read().await.cancelled_error())
+                }
             }
         }
+        Ok(())
     }
-    Ok(())
+    .await;
+    client_stat.reset_rate();
+    server_stat.reset_rate();
+    ret
 }
@@ -3,14 +3,16 @@ use easy_error::{bail, Error, ResultExt};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 use tracing::{error, info, warn};
 
+use crate::common::auth::AuthData;
 use crate::common::h11c::h11c_handshake;
-use crate::common::set_keepalive;
+use crate::common::proxy_protocol::ProxyProtocolConfig;
 use crate::common::tls::TlsServerConfig;
 use crate::context::{make_buffered_stream, ContextRef};
+use crate::listeners::stream::{BindAddress, Stream, StreamListener, UnixSocketOptions};
 use crate::listeners::Listener;
 use crate::GlobalState;
 
@@ -18,8 +20,13 @@ use crate::GlobalState;
 #[serde(rename_all = "camelCase")]
 pub struct HttpListener {
     name: String,
-    bind: SocketAddr,
+    bind: BindAddress,
+    #[serde(default)]
+    unix_socket: UnixSocketOptions,
     tls: Option<TlsServerConfig>,
+    #[serde(default)]
+    auth: AuthData,
+    proxy_protocol: Option<ProxyProtocolConfig>,
 }
 
 pub fn from_value(value: &serde_yaml::Value) -> Result<Box<dyn Listener>, Error> {
@@ -36,24 +43,27 @@ impl Listener for HttpListener {
         if let Some(Err(e)) = self.tls.as_mut().map(TlsServerConfig::init) {
             return Err(e);
         }
+        self.auth.init().await?;
+        if let Some(pp) = self.proxy_protocol.as_mut() {
+            pp.init()?;
+        }
         Ok(())
     }
     async fn listen(
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error> {
+    ) -> Result<JoinHandle<()>, Error> {
         info!("{} listening on {}", self.name, self.bind);
-        let listener = TcpListener::bind(&self.bind).await.context("bind")?;
+        let listener = StreamListener::bind(&self.bind, &self.unix_socket).await?;
         let this = self.clone();
-        tokio::spawn(this.accept(listener, state, queue));
-        Ok(())
+        Ok(tokio::spawn(this.accept(listener, state, queue)))
     }
 }
 impl HttpListener {
     async fn accept(
         self: Arc<Self>,
-        listener: TcpListener,
+        listener: StreamListener,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
     ) {
@@ -68,8 +78,10 @@ impl HttpListener {
                     tokio::spawn(async move {
                         let res = match this.create_context(state, source, socket).await {
                             Ok(ctx) => {
-                                h11c_handshake(ctx, queue, |_, _| async { bail!("not supported") })
-                                    .await
+                                h11c_handshake(ctx, queue, &this.auth, |_, _| async {
+                                    bail!("not supported")
+                                })
+                                .await
                             }
                             Err(e) => Err(e),
                         };
@@ -92,9 +104,15 @@ impl HttpListener {
         &self,
         state: Arc<GlobalState>,
         source: SocketAddr,
-        socket: TcpStream,
+        mut socket: Stream,
     ) -> Result<ContextRef, Error> {
-        set_keepalive(&socket)?;
+        socket.set_keepalive()?;
+        let local_addr = socket.local_addr()?;
+        let peer = socket.peer_credentials();
+        let header = match &self.proxy_protocol {
+            Some(pp) => pp.accept(&mut socket, source).await?,
+            None => None,
+        };
         let tls_acceptor = self.tls.as_ref().map(|options| options.acceptor());
         let stream = if let Some(acceptor) = tls_acceptor {
             acceptor
@@ -103,13 +121,22 @@ impl HttpListener {
                 .context("tls accept error")
                 .map(make_buffered_stream)?
         } else {
-            make_buffered_stream(socket)
+            socket.into_buffered()
         };
         let ctx = state
             .contexts
             .create_context(self.name.to_owned(), source)
             .await;
         ctx.write().await.set_client_stream(stream);
+        if let Some(addr) = local_addr {
+            ctx.write().await.set_listener_addr(addr);
+        }
+        if let Some(header) = header {
+            ctx.write().await.set_proxy_header(header);
+        }
+        if let Some(peer) = peer {
+            peer.record(&ctx).await;
+        }
         Ok(ctx)
     }
 }
@@ -3,13 +3,14 @@ use std::{collections::HashMap, sync::Arc};
 use async_trait::async_trait;
 use easy_error::{bail, err_msg, Error};
 use serde_yaml::Value;
-use tokio::sync::mpsc::Sender;
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
 
 use crate::{context::ContextRef, GlobalState};
 
 mod http;
 mod reverse;
 mod socks;
+mod stream;
 
 #[cfg(feature = "quic")]
 mod quic;
@@ -29,7 +30,7 @@ pub trait Listener: Send + Sync {
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error>;
+    ) -> Result<JoinHandle<()>, Error>;
     fn name(&self) -> &str;
 }
 
@@ -54,6 +55,7 @@ pub fn from_value(value: &Value) -> Result<Box<dyn Listener>, Error> {
     match tname {
         "http" => http::from_value(value),
         "socks" => socks::from_value(value),
+        "mixed" => socks::mixed_from_value(value),
         "reverse" => reverse::from_value(value),
 
         #[cfg(feature = "quic")]
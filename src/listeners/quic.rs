@@ -1,14 +1,16 @@
 use async_trait::async_trait;
 use chashmap_async::CHashMap;
-use easy_error::{Error, ResultExt};
+use easy_error::{ensure, Error, ResultExt};
 use futures_util::TryFutureExt;
 use quinn::{congestion, Connection, Endpoint};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 
+use crate::common::auth::AuthData;
 use crate::common::h11c::h11c_handshake;
 use crate::common::quic::{create_quic_frames, create_quic_server, quic_frames_thread, QuicStream};
 use crate::common::tls::TlsServerConfig;
@@ -24,6 +26,12 @@ pub struct QuicListener {
     tls: TlsServerConfig,
     #[serde(default = "default_bbr")]
     bbr: bool,
+    #[serde(default)]
+    auth: AuthData,
+    // PROXY protocol has no way to be carried over QUIC, only accepted
+    // here to reject configs expecting it to work
+    #[serde(default)]
+    proxy_protocol: Option<serde_yaml::Value>,
 }
 
 fn default_bbr() -> bool {
@@ -41,14 +49,19 @@ impl Listener for QuicListener {
         &self.name
     }
     async fn init(&mut self) -> Result<(), Error> {
+        ensure!(
+            self.proxy_protocol.is_none(),
+            "proxyProtocol is not supported on quic listener"
+        );
         self.tls.init()?;
+        self.auth.init().await?;
         Ok(())
     }
     async fn listen(
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error> {
+    ) -> Result<JoinHandle<()>, Error> {
         info!("{} listening on {}", self.name, self.bind);
         let mut cfg = create_quic_server(&self.tls)?;
         if self.bbr {
@@ -56,11 +69,10 @@ impl Listener for QuicListener {
             transport.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()));
         }
         let endpoint = Endpoint::server(cfg, self.bind).context("quic_listen")?;
-        tokio::spawn(
+        Ok(tokio::spawn(
             self.accept(endpoint, state, queue)
                 .unwrap_or_else(|e| panic!("{}: {:?}", e, e.cause)),
-        );
-        Ok(())
+        ))
     }
 }
 impl QuicListener {
@@ -109,18 +121,25 @@ impl QuicListener {
                 .contexts
                 .create_context(self.name.to_owned(), source)
                 .await;
-            ctx.write().await.set_client_stream(stream);
+            let mut inner = ctx.write().await;
+            inner.set_client_stream(stream);
+            if let Some(ip) = conn.local_ip() {
+                inner.set_listener_addr(SocketAddr::new(ip, self.bind.port()));
+            }
+            drop(inner);
             let this = self.clone();
             let conn = conn.clone();
             let sessions = sessions.clone();
-            tokio::spawn(
-                h11c_handshake(ctx, queue.clone(), |_ch, id| async move {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let res = h11c_handshake(ctx, queue, &this.auth, |_ch, id| async move {
                     Ok(create_quic_frames(conn, id, sessions).await)
                 })
-                .unwrap_or_else(move |e| {
+                .await;
+                if let Err(e) = res {
                     warn!("{}: h11c handshake error: {}: {:?}", this.name, e, e.cause)
-                }),
-            );
+                }
+            });
         }
     }
 }
@@ -1,19 +1,22 @@
 use async_trait::async_trait;
 use chashmap_async::CHashMap;
-use easy_error::{Error, ResultExt};
+use easy_error::{ensure, Error, ResultExt};
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{channel, Sender};
-use tracing::{debug, error, info};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 
+use super::stream::{BindAddress, Stream, StreamListener, UnixSocketOptions};
 use super::Listener;
 use crate::common::frames::Frame;
-use crate::common::set_keepalive;
+use crate::common::proxy_protocol::ProxyProtocolConfig;
+use crate::common::sniff::sniff_target;
 use crate::common::udp::{self, setup_udp_session, udp_socket};
-use crate::context::{make_buffered_stream, Context, ContextRef, Feature, TargetAddress};
+use crate::context::{Context, ContextRef, Feature, TargetAddress};
 use crate::context::{ContextCallback, ContextRefOps};
 use crate::GlobalState;
 
@@ -21,10 +24,16 @@ use crate::GlobalState;
 #[serde(rename_all = "camelCase")]
 pub struct ReverseProxyListener {
     name: String,
-    bind: SocketAddr,
+    bind: BindAddress,
+    #[serde(default)]
+    unix_socket: UnixSocketOptions,
     target: TargetAddress,
     #[serde(default = "default_protocol")]
     protocol: Protocol,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    // rewrites target to host name found in TLS ClientHello or HTTP request
+    #[serde(default)]
+    sniff: bool,
     #[serde(skip)]
     sessions: Arc<CHashMap<SocketAddr, udp::Sender>>,
 }
@@ -48,15 +57,33 @@ pub fn from_value(value: &Value) -> Result<Box<dyn Listener>, Error> {
 
 #[async_trait]
 impl Listener for ReverseProxyListener {
+    async fn init(&mut self) -> Result<(), Error> {
+        if let BindAddress::Unix(_) = self.bind {
+            ensure!(
+                matches!(self.protocol, Protocol::Tcp),
+                "unix socket is only supported with tcp protocol"
+            );
+            ensure!(!self.sniff, "sniff is not supported on unix socket");
+        }
+        if let Some(pp) = self.proxy_protocol.as_mut() {
+            ensure!(
+                matches!(self.protocol, Protocol::Tcp),
+                "proxyProtocol is only supported with tcp protocol"
+            );
+            pp.init()?;
+        }
+        Ok(())
+    }
+
     async fn listen(
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error> {
+    ) -> Result<JoinHandle<()>, Error> {
         info!("{} listening on {}", self.name, self.bind);
-        match self.protocol {
+        let handle = match self.protocol {
             Protocol::Tcp => {
-                let listener = TcpListener::bind(&self.bind).await.context("bind")?;
+                let listener = StreamListener::bind(&self.bind, &self.unix_socket).await?;
                 tokio::spawn(async move {
                     loop {
                         self.tcp_accept(&listener, &state, &queue)
@@ -65,10 +92,10 @@ impl Listener for ReverseProxyListener {
                                 error!("{}: accept error: {} \ncause: {:?}", self.name, e, e.cause)
                             });
                     }
-                });
+                })
             }
             Protocol::Udp => {
-                let socket = udp_socket(self.bind, None, false).context("bind")?;
+                let socket = udp_socket(self.udp_bind(), None, false).context("bind")?;
                 let listener = Arc::new(socket);
                 tokio::spawn(async move {
                     loop {
@@ -78,10 +105,10 @@ impl Listener for ReverseProxyListener {
                                 error!("{}: accept error: {} \ncause: {:?}", self.name, e, e.cause)
                             });
                     }
-                });
+                })
             }
-        }
-        Ok(())
+        };
+        Ok(handle)
     }
 
     fn name(&self) -> &str {
@@ -90,24 +117,67 @@ impl Listener for ReverseProxyListener {
 }
 
 impl ReverseProxyListener {
+    // init makes sure udp listeners are bound to an ip address
+    fn udp_bind(&self) -> SocketAddr {
+        self.bind.socket_addr().unwrap()
+    }
+
     async fn tcp_accept(
         self: &Arc<Self>,
-        listener: &TcpListener,
+        listener: &StreamListener,
         state: &Arc<GlobalState>,
         queue: &Sender<ContextRef>,
     ) -> Result<(), Error> {
         let (socket, source) = listener.accept().await.context("accept")?;
         let source = crate::common::try_map_v4_addr(source);
-        set_keepalive(&socket)?;
+        socket.set_keepalive()?;
         debug!("{}: connected from {:?}", self.name, source);
+        if self.proxy_protocol.is_none() && !self.sniff {
+            return self.tcp_handshake(socket, source, state, queue).await;
+        }
+        // reading from client may take a while, don't block accepting
+        let this = self.clone();
+        let state = state.clone();
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            if let Err(e) = this.tcp_handshake(socket, source, &state, &queue).await {
+                warn!("{}: handshake error: {} cause: {:?}", this.name, e, e.cause);
+            }
+        });
+        Ok(())
+    }
+
+    async fn tcp_handshake(
+        &self,
+        mut socket: Stream,
+        source: SocketAddr,
+        state: &Arc<GlobalState>,
+        queue: &Sender<ContextRef>,
+    ) -> Result<(), Error> {
+        let local_addr = socket.local_addr()?;
+        let peer = socket.peer_credentials();
+        let header = match &self.proxy_protocol {
+            Some(pp) => pp.accept(&mut socket, source).await?,
+            None => None,
+        };
         let ctx = state
             .contexts
             .create_context(self.name.to_owned(), source)
             .await;
-        ctx.write()
-            .await
-            .set_target(self.target.clone())
-            .set_client_stream(make_buffered_stream(socket));
+        if let Some(addr) = local_addr {
+            ctx.write().await.set_listener_addr(addr);
+        }
+        if let Some(header) = header {
+            ctx.write().await.set_proxy_header(header);
+        }
+        if let Some(peer) = peer {
+            peer.record(&ctx).await;
+        }
+        ctx.write().await.set_target(self.target.clone());
+        if let (true, Stream::Tcp(socket)) = (self.sniff, &socket) {
+            sniff_target(socket, &ctx).await;
+        }
+        ctx.write().await.set_client_stream(socket.into_buffered());
         ctx.enqueue(queue).await?;
         Ok(())
     }
@@ -128,7 +198,7 @@ impl ReverseProxyListener {
             tx.send(buf).await.context("send")?;
         } else {
             let (tx, rx) = channel(100);
-            let io = setup_udp_session(self.target.clone(), self.bind, source, rx, false)
+            let io = setup_udp_session(self.target.clone(), self.udp_bind(), source, rx, false)
                 .context("setup session")?;
             self.sessions.insert(source, tx).await;
             let ctx = state
@@ -1,30 +1,35 @@
 use async_trait::async_trait;
-use easy_error::{err_msg, Error, ResultExt};
+use easy_error::{bail, ensure, err_msg, Error, ResultExt};
 use futures::TryFutureExt;
 use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc::Sender,
-};
+use tokio::{io::AsyncBufReadExt, sync::mpsc::Sender, task::JoinHandle};
 use tracing::{debug, error, info, warn};
 
 use crate::{
     common::{
         auth::AuthData,
-        into_unspecified, set_keepalive,
+        h11c::h11c_handshake,
+        into_unspecified,
+        proxy_protocol::ProxyProtocolConfig,
         socks::{
             frames::setup_udp_session, PasswordAuth, SocksRequest, SocksResponse, SOCKS_CMD_BIND,
             SOCKS_CMD_CONNECT, SOCKS_CMD_UDP_ASSOCIATE, SOCKS_REPLY_GENERAL_FAILURE,
-            SOCKS_REPLY_OK,
+            SOCKS_REPLY_OK, SOCKS_VER_4, SOCKS_VER_5,
         },
         tls::TlsServerConfig,
     },
-    context::{make_buffered_stream, Context, ContextCallback, ContextRef, ContextRefOps, Feature},
-    listeners::Listener,
+    context::{
+        make_buffered_stream, Context, ContextCallback, ContextRef, ContextRefOps, Feature,
+        IOBufStream, TargetAddress,
+    },
+    listeners::{
+        stream::{BindAddress, Stream, StreamListener, UnixSocketOptions},
+        Listener,
+    },
     GlobalState,
 };
 
@@ -32,7 +37,9 @@ use crate::{
 #[serde(rename_all = "camelCase")]
 pub struct SocksListener {
     name: String,
-    bind: SocketAddr,
+    bind: BindAddress,
+    #[serde(default)]
+    unix_socket: UnixSocketOptions,
     tls: Option<TlsServerConfig>,
     #[serde(default)]
     auth: AuthData,
@@ -42,6 +49,10 @@ pub struct SocksListener {
     enforce_udp_client: bool,
     #[serde(default)]
     override_udp_address: Option<IpAddr>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    // also serves http clients on the same port
+    #[serde(skip)]
+    mixed: bool,
 }
 
 fn default_allow_udp() -> bool {
@@ -53,6 +64,13 @@ pub fn from_value(value: &serde_yaml::Value) -> Result<Box<dyn Listener>, Error>
     Ok(Box::new(ret))
 }
 
+/// Listener serving both socks and http clients, telling them apart by the first byte.
+pub fn mixed_from_value(value: &serde_yaml::Value) -> Result<Box<dyn Listener>, Error> {
+    let mut ret: SocksListener = serde_yaml::from_value(value.clone()).context("parse config")?;
+    ret.mixed = true;
+    Ok(Box::new(ret))
+}
+
 #[async_trait]
 impl Listener for SocksListener {
     async fn init(&mut self) -> Result<(), Error> {
@@ -60,18 +78,20 @@ impl Listener for SocksListener {
             return Err(e);
         }
         self.auth.init().await?;
+        if let Some(pp) = self.proxy_protocol.as_mut() {
+            pp.init()?;
+        }
         Ok(())
     }
     async fn listen(
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error> {
+    ) -> Result<JoinHandle<()>, Error> {
         info!("{} listening on {}", self.name, self.bind);
-        let listener = TcpListener::bind(&self.bind).await.context("bind")?;
+        let listener = StreamListener::bind(&self.bind, &self.unix_socket).await?;
         let this = self.clone();
-        tokio::spawn(this.accept(listener, state, queue));
-        Ok(())
+        Ok(tokio::spawn(this.accept(listener, state, queue)))
     }
 
     fn name(&self) -> &str {
@@ -82,7 +102,7 @@ impl Listener for SocksListener {
 impl SocksListener {
     async fn accept(
         self: Arc<Self>,
-        listener: TcpListener,
+        listener: StreamListener,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
     ) {
@@ -116,23 +136,45 @@ impl SocksListener {
 
     async fn handshake(
         self: Arc<Self>,
-        socket: TcpStream,
+        mut socket: Stream,
         source: SocketAddr,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
     ) -> Result<(), Error> {
-        let local_addr = socket.local_addr().context("local_addr")?;
-        set_keepalive(&socket)?;
+        let local_addr = socket.local_addr()?;
+        socket.set_keepalive()?;
+        let peer = socket.peer_credentials();
+        let header = match &self.proxy_protocol {
+            Some(pp) => pp.accept(&mut socket, source).await?,
+            None => None,
+        };
         let tls_acceptor = self.tls.as_ref().map(|options| options.acceptor());
         let mut socket = if let Some(acceptor) = tls_acceptor {
             make_buffered_stream(acceptor.accept(socket).await.context("tls accept error")?)
         } else {
-            make_buffered_stream(socket)
+            socket.into_buffered()
         };
         let ctx = state
             .contexts
             .create_context(self.name.to_owned(), source)
             .await;
+        if let Some(addr) = local_addr {
+            ctx.write().await.set_listener_addr(addr);
+        }
+        if let Some(header) = header {
+            ctx.write().await.set_proxy_header(header);
+        }
+        if let Some(peer) = peer {
+            peer.record(&ctx).await;
+        }
+
+        if self.mixed && !is_socks(&mut socket).await? {
+            ctx.write().await.set_client_stream(socket);
+            return h11c_handshake(ctx, queue, &self.auth, |_, _| async {
+                bail!("not supported")
+            })
+            .await;
+        }
 
         let auth_server = PasswordAuth {
             required: self.auth.required,
@@ -163,8 +205,11 @@ impl SocksListener {
                 ctx.enqueue(&queue).await?;
             }
             SOCKS_CMD_BIND => {
-                ctx.on_error(err_msg("not supported")).await;
-                debug!("not supported cmd: {:?}", request.cmd);
+                ctx.write()
+                    .await
+                    .set_target(request.target)
+                    .set_feature(Feature::TcpBind);
+                ctx.enqueue(&queue).await?;
             }
             SOCKS_CMD_UDP_ASSOCIATE => {
                 if !self.allow_udp {
@@ -172,6 +217,15 @@ impl SocksListener {
                     debug!("udp not allowed");
                     return Ok(());
                 }
+                // clients of unix sockets have no address to send datagrams from
+                let local_addr = match local_addr {
+                    Some(x) => x,
+                    None => {
+                        ctx.on_error(err_msg("not supported")).await;
+                        debug!("udp not available on unix socket");
+                        return Ok(());
+                    }
+                };
                 let local = into_unspecified(source);
                 let remote = if self.enforce_udp_client {
                     request
@@ -213,6 +267,13 @@ impl SocksListener {
     }
 }
 
+// peeks the first byte sent by client without consuming it
+async fn is_socks(socket: &mut IOBufStream) -> Result<bool, Error> {
+    let buf = socket.fill_buf().await.context("read")?;
+    ensure!(!buf.is_empty(), "connection closed");
+    Ok(buf[0] == SOCKS_VER_4 || buf[0] == SOCKS_VER_5)
+}
+
 struct Callback {
     version: u8,
     listen_addr: Option<SocketAddr>,
@@ -223,7 +284,14 @@ impl ContextCallback for Callback {
     async fn on_connect(&self, ctx: &mut Context) {
         let version = self.version;
         let cmd = SOCKS_REPLY_OK;
-        let target = self.listen_addr.map_or_else(|| ctx.target(), |x| x.into());
+        let target = if ctx.feature() == Feature::TcpBind {
+            // second reply of BIND, carries address of the connected peer
+            ctx.extra("tcp-bind-peer")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or_else(|| ctx.server_addr().into())
+        } else {
+            self.listen_addr.map_or_else(|| ctx.target(), |x| x.into())
+        };
         let socket = ctx.borrow_client_stream();
         let resp = SocksResponse {
             version,
@@ -234,6 +302,20 @@ impl ContextCallback for Callback {
             warn!("failed to send response: {}", e)
         }
     }
+    async fn on_bind_listen(&self, ctx: &mut Context, addr: TargetAddress) {
+        let resp = SocksResponse {
+            version: self.version,
+            cmd: SOCKS_REPLY_OK,
+            target: addr,
+        };
+        if let Some(e) = resp
+            .write_to(ctx.borrow_client_stream().unwrap())
+            .await
+            .err()
+        {
+            warn!("failed to send response: {}", e)
+        }
+    }
     async fn on_error(&self, ctx: &mut Context, _error: Error) {
         let version = self.version;
         let cmd = SOCKS_REPLY_GENERAL_FAILURE;
@@ -252,3 +334,80 @@ impl ContextCallback for Callback {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connectors::test_state, context::ContextRefOps};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpSocket, TcpStream},
+    };
+
+    async fn sniff(data: &[u8]) -> (Result<bool, Error>, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(data).await.unwrap();
+        drop(client);
+        let mut server = make_buffered_stream(server);
+        let ret = is_socks(&mut server).await;
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        (ret, rest)
+    }
+
+    #[tokio::test]
+    async fn mixed_dispatch() {
+        let (ret, rest) = sniff(b"\x05\x01\x00").await;
+        assert!(ret.unwrap());
+        assert_eq!(rest, b"\x05\x01\x00");
+        let (ret, _) = sniff(b"\x04\x01\x00\x50").await;
+        assert!(ret.unwrap());
+        let req = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        let (ret, rest) = sniff(req).await;
+        assert!(!ret.unwrap());
+        assert_eq!(rest, req);
+        assert!(sniff(b"").await.0.is_err());
+    }
+
+    // BIND is answered twice, with the address to connect to,
+    // then with the address of the peer connected
+    #[tokio::test]
+    async fn bind_replies() {
+        let state = test_state("[{name: direct}]").await.unwrap();
+        let direct = state.connectors.read().await["direct"].clone();
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = make_buffered_stream(client);
+        let ctx = state
+            .contexts
+            .create_context("socks".into(), "127.0.0.1:1234".parse().unwrap())
+            .await;
+        ctx.write()
+            .await
+            .set_target("127.0.0.1:0".parse().unwrap())
+            .set_feature(Feature::TcpBind)
+            .set_idle_timeout(10)
+            .set_callback(Callback {
+                version: SOCKS_VER_5,
+                listen_addr: None,
+            })
+            .set_client_stream(make_buffered_stream(server));
+        let connect = tokio::spawn(direct.connect(state.clone(), ctx.clone()));
+
+        let first = SocksResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(first.cmd, SOCKS_REPLY_OK);
+        let listen = first.target.as_socket_addr().unwrap();
+        // hosts other than the one in request are turned away
+        let other = TcpSocket::new_v4().unwrap();
+        other.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut other = other.connect(listen).await.unwrap();
+        assert_eq!(other.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(!connect.is_finished());
+
+        let peer = TcpStream::connect(listen).await.unwrap();
+        connect.await.unwrap().unwrap();
+        ctx.on_connect().await;
+        let second = SocksResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(second.cmd, SOCKS_REPLY_OK);
+        assert_eq!(second.target, peer.local_addr().unwrap().into());
+    }
+}
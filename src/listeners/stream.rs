@@ -0,0 +1,476 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::Result as IoResult,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use easy_error::{bail, Error, ResultExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{
+    common::{proxy_protocol::Peek, set_keepalive},
+    context::{make_buffered_stream, ContextRef, IOBufStream},
+};
+
+/// Address of a stream listener, `ip:port` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddress {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => bail!("empty unix socket path"),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => Ok(Self::Tcp(
+                s.parse()
+                    .with_context(|| format!("invalid address: {}", s))?,
+            )),
+        }
+    }
+}
+
+impl Display for BindAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for BindAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl BindAddress {
+    /// The ip address, for protocols not available on unix sockets.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(addr) => Some(*addr),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+/// Owner and permissions of unix socket created by listener.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UnixSocketOptions {
+    // octal file mode, e.g. "660"
+    mode: Option<String>,
+    // name or numeric id
+    owner: Option<String>,
+    group: Option<String>,
+}
+
+impl UnixSocketOptions {
+    #[cfg(unix)]
+    fn apply(&self, path: &std::path::Path) -> Result<(), Error> {
+        use nix::unistd::{chown, Gid, Group, Uid, User};
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = &self.mode {
+            let mode = u32::from_str_radix(mode, 8)
+                .with_context(|| format!("invalid socket mode: {}", mode))?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .context("chmod")?;
+        }
+        let uid = match &self.owner {
+            None => None,
+            Some(s) => Some(match s.parse() {
+                Ok(uid) => Uid::from_raw(uid),
+                Err(_) => {
+                    User::from_name(s)
+                        .context("getpwnam")?
+                        .ok_or_else(|| easy_error::err_msg(format!("no such user: {}", s)))?
+                        .uid
+                }
+            }),
+        };
+        let gid = match &self.group {
+            None => None,
+            Some(s) => Some(match s.parse() {
+                Ok(gid) => Gid::from_raw(gid),
+                Err(_) => {
+                    Group::from_name(s)
+                        .context("getgrnam")?
+                        .ok_or_else(|| easy_error::err_msg(format!("no such group: {}", s)))?
+                        .gid
+                }
+            }),
+        };
+        if uid.is_some() || gid.is_some() {
+            chown(path, uid, gid).context("chown")?;
+        }
+        Ok(())
+    }
+}
+
+/// Listens on either a tcp address or a unix socket.
+pub enum StreamListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl StreamListener {
+    pub async fn bind(addr: &BindAddress, options: &UnixSocketOptions) -> Result<Self, Error> {
+        match addr {
+            BindAddress::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await.context("bind")?)),
+            #[cfg(unix)]
+            BindAddress::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                // left by a previous run, unless someone is still listening on it
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        if tokio::net::UnixStream::connect(path).await.is_ok() {
+                            bail!("address in use: {}", path.display());
+                        }
+                        std::fs::remove_file(path).context("remove stale socket")?;
+                    }
+                }
+                let listener = bind_private(path, options)?;
+                Ok(Self::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            BindAddress::Unix(_) => {
+                let _ = options;
+                bail!("unix socket is not supported on this platform")
+            }
+        }
+    }
+
+    /// Accepts a connection, peers of unix sockets have an unspecified address.
+    pub async fn accept(&self) -> IoResult<(Stream, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, source) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), source))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((
+                    Stream::Unix(stream),
+                    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+                ))
+            }
+        }
+    }
+}
+
+// Binds in a private directory next to `path` and links the socket into place
+// once its mode and owner are set, so it is never reachable with looser permissions.
+#[cfg(unix)]
+fn bind_private(
+    path: &std::path::Path,
+    options: &UnixSocketOptions,
+) -> Result<UnixListener, Error> {
+    use std::os::unix::fs::DirBuilderExt;
+    let parent = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => std::path::Path::new("."),
+    };
+    let dir = parent.join(format!(".redproxy-{:08x}", rand::random::<u32>()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .context("create private directory")?;
+    let tmp = dir.join("sock");
+    let ret = UnixListener::bind(&tmp)
+        .context("bind")
+        .and_then(|listener| {
+            options.apply(&tmp)?;
+            // unlike rename, fails if something is at path already
+            std::fs::hard_link(&tmp, path).with_context(|| format!("bind {}", path.display()))?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    ret
+}
+
+#[cfg(unix)]
+impl Drop for StreamListener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Credentials of a unix socket peer.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// Keeps credentials in extras, where rules can find them as `request.peer`.
+    pub async fn record(&self, ctx: &ContextRef) {
+        let mut ctx = ctx.write().await;
+        ctx.set_extra("peer-uid", self.uid)
+            .set_extra("peer-gid", self.gid);
+        if let Some(pid) = self.pid {
+            ctx.set_extra("peer-pid", pid);
+        }
+    }
+}
+
+/// An accepted connection.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn set_keepalive(&self) -> Result<(), Error> {
+        match self {
+            Self::Tcp(stream) => set_keepalive(stream),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Local ip address, None for unix sockets.
+    pub fn local_addr(&self) -> Result<Option<SocketAddr>, Error> {
+        match self {
+            Self::Tcp(stream) => Ok(Some(stream.local_addr().context("local_addr")?)),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(None),
+        }
+    }
+
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Self::Tcp(_) => None,
+            #[cfg(unix)]
+            Self::Unix(stream) => stream
+                .peer_cred()
+                .map_err(|e| warn!("failed to get peer credentials: {}", e))
+                .ok()
+                .map(|cred| PeerCredentials {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                }),
+        }
+    }
+
+    // the inner stream is buffered as it is, so tcp streams are still eligible for splice
+    pub fn into_buffered(self) -> IOBufStream {
+        match self {
+            Self::Tcp(stream) => make_buffered_stream(stream),
+            #[cfg(unix)]
+            Self::Unix(stream) => make_buffered_stream(stream),
+        }
+    }
+}
+
+#[async_trait]
+impl Peek for Stream {
+    async fn peek(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            Self::Tcp(stream) => stream.peek(buf).await,
+            // tokio has no peek for unix sockets
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                use nix::sys::socket::{recv, MsgFlags};
+                use std::os::unix::io::AsRawFd;
+                use tokio::io::Interest;
+                loop {
+                    stream.readable().await?;
+                    match stream.try_io(Interest::READABLE, || {
+                        recv(stream.as_raw_fd(), buf, MsgFlags::MSG_PEEK).map_err(Into::into)
+                    }) {
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        ret => return ret,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::proxy_protocol::{ProxyHeader, ProxyProtocolConfig};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    fn expected() -> Option<ProxyHeader> {
+        Some(ProxyHeader {
+            source: "1.1.1.1:1".parse().unwrap(),
+            destination: "2.2.2.2:2".parse().unwrap(),
+        })
+    }
+
+    // sends a header in two segments, returns it and the data following it
+    async fn split_header<W>(mut client: W, mut server: Stream) -> (Option<ProxyHeader>, [u8; 4])
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+        let cfg: ProxyProtocolConfig = serde_yaml::from_str("timeout: 1").unwrap();
+        let peer = "10.0.0.1:1".parse().unwrap();
+        let read = tokio::spawn(async move {
+            let ret = cfg.accept(&mut server, peer).await.unwrap();
+            let mut rest = [0u8; 4];
+            server.read_exact(&mut rest).await.unwrap();
+            (ret, rest)
+        });
+        client.write_all(b"PROXY TCP4 1.1.1.1 ").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"2.2.2.2 1 2\r\nrest").await.unwrap();
+        read.await.unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_mode() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        let dir = std::env::temp_dir().join(format!("redproxy-test-{:08x}", rand::random::<u32>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("proxy.sock");
+        let options: UnixSocketOptions = serde_yaml::from_str("mode: '600'").unwrap();
+        let listener = StreamListener::bind(&BindAddress::Unix(path.clone()), &options)
+            .await
+            .unwrap();
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // nothing is left of the private directory
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _client = UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+
+        // files other than sockets are never replaced
+        let file = dir.join("file");
+        std::fs::write(&file, "data").unwrap();
+        assert!(
+            StreamListener::bind(&BindAddress::Unix(file.clone()), &options)
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read(&file).unwrap(), b"data");
+
+        drop(listener);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn peek_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let server = Stream::Tcp(listener.accept().await.unwrap().0);
+        let (ret, rest) = split_header(client, server).await;
+        assert_eq!(ret, expected());
+        assert_eq!(&rest, b"rest");
+
+        #[cfg(unix)]
+        {
+            let (client, server) = tokio::net::UnixStream::pair().unwrap();
+            let (ret, rest) = split_header(client, Stream::Unix(server)).await;
+            assert_eq!(ret, expected());
+            assert_eq!(&rest, b"rest");
+        }
+
+        // client sending nothing is dropped after timeout
+        let cfg: ProxyProtocolConfig = serde_yaml::from_str("timeout: 1").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut server = Stream::Tcp(listener.accept().await.unwrap().0);
+        let peer = "10.0.0.1:1".parse().unwrap();
+        let ret = tokio::time::timeout(Duration::from_secs(3), cfg.accept(&mut server, peer))
+            .await
+            .unwrap();
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn bind_address() {
+        let addr: BindAddress = serde_yaml::from_str("unix:/run/redproxy.sock").unwrap();
+        assert_eq!(addr, BindAddress::Unix("/run/redproxy.sock".into()));
+        assert_eq!(addr.to_string(), "unix:/run/redproxy.sock");
+        assert_eq!(addr.socket_addr(), None);
+        let addr: BindAddress = serde_yaml::from_str("'[::1]:8080'").unwrap();
+        assert_eq!(addr.socket_addr(), Some("[::1]:8080".parse().unwrap()));
+        assert!("unix:".parse::<BindAddress>().is_err());
+        assert!("localhost:80".parse::<BindAddress>().is_err());
+    }
+}
@@ -27,18 +27,21 @@ use std::{
 };
 use tokio::{
     io::unix::AsyncFd,
-    net::{TcpListener, UdpSocket},
+    net::{TcpListener, TcpSocket, UdpSocket},
     sync::{
         mpsc::{channel, Receiver, Sender},
         Mutex,
     },
+    task::JoinHandle,
 };
 use tracing::{debug, error, info, trace};
 
 use crate::{
     common::{
         frames::{Frame, FrameReader, FrameWriter},
-        into_unspecified, set_keepalive, try_map_v4_addr,
+        into_unspecified, set_keepalive,
+        sniff::sniff_target,
+        try_map_v4_addr,
         udp::{setup_udp_session, udp_socket},
     },
     context::{
@@ -57,10 +60,16 @@ pub struct TProxyListener {
     bind: SocketAddr,
     #[serde(default = "default_protocol")]
     protocol: Protocol,
+    // how tcp connections are redirected to us, udp always requires tproxy
+    #[serde(default = "default_mode")]
+    mode: Mode,
     #[serde(default = "default_max_udp_socket")]
     max_udp_socket: usize,
     #[serde(default)]
     udp_full_cone: bool,
+    // rewrites target to host name found in TLS ClientHello or HTTP request
+    #[serde(default)]
+    sniff: bool,
     #[serde(skip)]
     inner: Option<Arc<Internals>>,
 }
@@ -72,6 +81,15 @@ pub enum Protocol {
     Udp,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Mode {
+    // iptables REDIRECT, destination is read with SO_ORIGINAL_DST
+    Redirect,
+    // iptables TPROXY, destination is the local address of accepted socket
+    Tproxy,
+}
+
 struct Internals {
     sessions: CHashMap<(SocketAddr, SocketAddr), Session>,
     sockets: Mutex<LruCache<SocketAddr, UdpSocket>>,
@@ -81,6 +99,10 @@ fn default_protocol() -> Protocol {
     Protocol::Tcp
 }
 
+fn default_mode() -> Mode {
+    Mode::Redirect
+}
+
 fn default_max_udp_socket() -> usize {
     128
 }
@@ -109,14 +131,17 @@ impl Listener for TProxyListener {
         self: Arc<Self>,
         state: Arc<GlobalState>,
         queue: Sender<ContextRef>,
-    ) -> Result<(), Error> {
+    ) -> Result<JoinHandle<()>, Error> {
         info!(
-            "{} listening on {} protocol: {:?}",
-            self.name, self.bind, self.protocol
+            "{} listening on {} protocol: {:?} mode: {:?}",
+            self.name, self.bind, self.protocol, self.mode
         );
-        match self.protocol {
+        let handle = match self.protocol {
             Protocol::Tcp => {
-                let listener = TcpListener::bind(&self.bind).await.context("bind")?;
+                let listener = match self.mode {
+                    Mode::Redirect => TcpListener::bind(&self.bind).await.context("bind")?,
+                    Mode::Tproxy => transparent_tcp_listener(self.bind).context("bind")?,
+                };
                 tokio::spawn(async move {
                     loop {
                         self.clone()
@@ -127,7 +152,7 @@ impl Listener for TProxyListener {
                             })
                             .unwrap_or(());
                     }
-                });
+                })
             }
             Protocol::Udp => {
                 let listener = TproxyUdpSocket::bind(self.bind).context("bind")?;
@@ -141,10 +166,10 @@ impl Listener for TProxyListener {
                             })
                             .unwrap_or(());
                     }
-                });
+                })
             }
-        }
-        Ok(())
+        };
+        Ok(handle)
     }
 
     fn name(&self) -> &str {
@@ -164,7 +189,9 @@ impl TProxyListener {
         set_keepalive(&socket)?;
         let source = crate::common::try_map_v4_addr(source);
 
-        let target = if source.is_ipv4() {
+        let target = if self.mode == Mode::Tproxy {
+            try_map_v4_addr(socket.local_addr().context("local_addr")?).into()
+        } else if source.is_ipv4() {
             let dst = getsockopt(socket.as_raw_fd(), OriginalDst).context("getsockopt")?;
             let addr = Ipv4Addr::from(ntohl(dst.sin_addr.s_addr));
             let port = ntohs(dst.sin_port);
@@ -181,11 +208,30 @@ impl TProxyListener {
             .contexts
             .create_context(self.name.to_owned(), source)
             .await;
-        ctx.write()
-            .await
-            .set_target(target)
-            .set_client_stream(make_buffered_stream(socket));
-        ctx.enqueue(queue).await?;
+        let mut inner = ctx.write().await;
+        // the original destination, not the port connections are redirected to
+        if let Some(addr) = target.as_socket_addr() {
+            inner.set_listener_addr(addr);
+        }
+        inner.set_target(target);
+        drop(inner);
+        if !self.sniff {
+            ctx.write()
+                .await
+                .set_client_stream(make_buffered_stream(socket));
+            return ctx.enqueue(queue).await;
+        }
+        // sniffing waits for client, don't block accepting
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            sniff_target(&socket, &ctx).await;
+            ctx.write()
+                .await
+                .set_client_stream(make_buffered_stream(socket));
+            if let Err(e) = ctx.enqueue(&queue).await {
+                error!("{}: enqueue error: {} cause: {:?}", self.name, e, e.cause);
+            }
+        });
         Ok(())
     }
 
@@ -290,6 +336,19 @@ struct TproxyUdpSocket {
     inner: AsyncFd<RawFd>,
 }
 
+// requires CAP_NET_ADMIN to accept connections destined to non-local addresses
+fn transparent_tcp_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    setsockopt(socket.as_raw_fd(), IpTransparent, &true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
 impl TproxyUdpSocket {
     pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
         let ss: SockaddrStorage = addr.into();
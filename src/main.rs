@@ -1,10 +1,17 @@
+use bandwidth::BandwidthLimits;
 use clap::{builder::PossibleValuesParser, value_parser};
 use config::{IoParams, Timeouts};
 use context::{ContextRef, ContextState, GlobalState as ContextGlobalState};
 use easy_error::{err_msg, Error, Terminator};
 use rules::Rule;
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc::channel, RwLock, RwLockReadGuard};
+use tokio::{
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex, RwLock, RwLockReadGuard,
+    },
+    task::JoinHandle,
+};
 use tracing::{info, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
@@ -12,13 +19,18 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use metrics::MetricsServer;
 
 mod access_log;
+mod bandwidth;
 mod common;
 mod config;
+mod connection_limits;
 mod connectors;
 mod context;
 mod copy;
 mod listeners;
+mod quota;
+mod reload;
 mod rules;
+mod shutdown;
 
 #[cfg(feature = "metrics")]
 mod metrics;
@@ -30,32 +42,25 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[derive(Default)]
 pub struct GlobalState {
     rules: RwLock<Vec<Arc<Rule>>>,
-    listeners: HashMap<String, Arc<dyn Listener>>,
-    connectors: HashMap<String, Arc<dyn Connector>>,
+    listeners: RwLock<HashMap<String, Arc<dyn Listener>>>,
+    connectors: RwLock<HashMap<String, Arc<dyn Connector>>>,
     contexts: Arc<ContextGlobalState>,
     timeouts: Timeouts,
     #[cfg(feature = "metrics")]
     metrics: Option<Arc<MetricsServer>>,
     io_params: IoParams,
+    bandwidth: BandwidthLimits,
+    config_path: String,
+    // raw content of the active config file, locked for the whole reload
+    config: Mutex<serde_yaml::Value>,
+    listener_tasks: Mutex<HashMap<String, JoinHandle<()>>>,
+    queue: Option<Sender<ContextRef>>,
 }
 
 impl GlobalState {
     async fn set_rules(&self, mut rules: Vec<Arc<Rule>>) -> Result<(), Error> {
-        for r in rules.iter_mut() {
-            Arc::get_mut(r).unwrap().init()?;
-        }
-
-        let connectors = &self.connectors;
-        rules.iter_mut().try_for_each(move |r| {
-            if r.target_name() == "deny" {
-                Ok(())
-            } else if let Some(t) = connectors.get(r.target_name()) {
-                Arc::get_mut(r).unwrap().target = Some(t.clone());
-                Ok(())
-            } else {
-                Err(err_msg(format!("target not found: {}", r.target_name())))
-            }
-        })?;
+        let connectors = self.connectors.read().await;
+        link_rules(&mut rules, &connectors)?;
         *self.rules.write().await = rules;
         Ok(())
     }
@@ -63,6 +68,26 @@ impl GlobalState {
         self.rules.read().await
     }
 }
+
+fn link_rules(
+    rules: &mut [Arc<Rule>],
+    connectors: &HashMap<String, Arc<dyn Connector>>,
+) -> Result<(), Error> {
+    for r in rules.iter_mut() {
+        Arc::get_mut(r).unwrap().init()?;
+    }
+
+    rules.iter_mut().try_for_each(move |r| {
+        if r.target_name() == "deny" {
+            Ok(())
+        } else if let Some(t) = connectors.get(r.target_name()) {
+            Arc::get_mut(r).unwrap().target = Some(t.clone());
+            Ok(())
+        } else {
+            Err(err_msg(format!("target not found: {}", r.target_name())))
+        }
+    })
+}
 #[tokio::main]
 async fn main() -> Result<(), Terminator> {
     let args = clap::Command::new(env!("CARGO_BIN_NAME"))
@@ -111,21 +136,24 @@ async fn main() -> Result<(), Terminator> {
         )
         .init();
 
-    let cfg = config::Config::load(config).await?;
+    let raw_cfg = config::Config::load_value(config).await?;
+    let cfg = config::Config::from_value(raw_cfg.clone())?;
+    let (tx, mut rx) = channel(100);
     let mut state: Arc<GlobalState> = Default::default();
     {
         let st_mut = Arc::get_mut(&mut state).unwrap();
         let ctx_mut = Arc::get_mut(&mut st_mut.contexts).unwrap();
-        ctx_mut.default_timeout = st_mut.timeouts.idle;
 
         st_mut.timeouts = cfg.timeouts;
-        st_mut.listeners = listeners::from_config(&cfg.listeners)?;
-        st_mut.connectors = connectors::from_config(&cfg.connectors)?;
+        ctx_mut.default_timeout = st_mut.timeouts.idle;
+        *st_mut.listeners.get_mut() = listeners::from_config(&cfg.listeners)?;
+        *st_mut.connectors.get_mut() = connectors::from_config(&cfg.connectors)?;
 
         #[cfg(feature = "metrics")]
         if let Some(mut metrics) = cfg.metrics {
             metrics.init()?;
             ctx_mut.history_size = metrics.history_size;
+            ctx_mut.enable_events();
             st_mut.metrics = Some(Arc::new(metrics));
         }
 
@@ -134,49 +162,95 @@ async fn main() -> Result<(), Terminator> {
             ctx_mut.access_log = Some(log);
         }
 
-        for l in st_mut.listeners.values_mut() {
+        if let Some(mut quotas) = cfg.quotas {
+            quotas.init().await?;
+            ctx_mut.quotas = Some(Arc::new(quotas));
+        }
+
+        if let Some(limits) = cfg.connection_limits {
+            limits.init()?;
+            ctx_mut.connection_limits = Some(Arc::new(limits));
+        }
+
+        for l in st_mut.listeners.get_mut().values_mut() {
             Arc::get_mut(l).unwrap().init().await?;
         }
 
-        for c in st_mut.connectors.values_mut() {
+        for c in st_mut.connectors.get_mut().values_mut() {
             Arc::get_mut(c).unwrap().init().await?;
         }
 
         st_mut.set_rules(rules::from_config(&cfg.rules)?).await?;
         st_mut.io_params = cfg.io_params;
+        st_mut.bandwidth = cfg.bandwidth;
+        st_mut.bandwidth.init()?;
+        st_mut.config_path = config.to_owned();
+        *st_mut.config.get_mut() = raw_cfg;
+        st_mut.queue = Some(tx);
     }
 
-    for l in state.listeners.values() {
+    for l in state.listeners.read().await.values() {
         l.verify(state.clone()).await?;
     }
 
-    for c in state.connectors.values() {
-        c.verify(state.clone()).await?;
+    let connectors = state.connectors.read().await;
+    for c in connectors.values() {
+        c.verify(&connectors).await?;
     }
+    drop(connectors);
 
     if config_test {
         println!("redproxy: the configuration file {} is ok", config);
         return Ok(());
     }
 
-    let (tx, mut rx) = channel(100);
-    for l in state.listeners.values().cloned() {
-        l.listen(state.clone(), tx.clone()).await?;
+    for c in state.connectors.read().await.values().cloned() {
+        c.start(state.clone());
     }
 
+    let queue = state.queue.clone().unwrap();
+    let mut tasks = state.listener_tasks.lock().await;
+    for l in state.listeners.read().await.values().cloned() {
+        let name = l.name().to_owned();
+        tasks.insert(name, l.listen(state.clone(), queue.clone()).await?);
+    }
+    drop(tasks);
+
     #[cfg(feature = "metrics")]
     if let Some(metrics) = state.metrics.clone() {
         metrics.listen(state.clone()).await?;
     }
     state.contexts.clone().gc_thread();
-
-    loop {
-        let ctx = rx.recv().await.unwrap();
-        tokio::spawn(process_request(ctx, state.clone()));
+    if let Some(quotas) = state.contexts.quotas.clone() {
+        quotas.start(state.contexts.clone());
     }
+    tokio::spawn(reload::signal_watch(state.clone()));
+
+    let st = state.clone();
+    tokio::spawn(async move {
+        while let Some(ctx) = rx.recv().await {
+            tokio::spawn(process_request(ctx, st.clone()));
+        }
+    });
+
+    shutdown::signal().await;
+    state.shutdown().await?;
+    Ok(())
 }
 
 async fn process_request(ctx: ContextRef, state: Arc<GlobalState>) {
+    if let Some(quotas) = &state.contexts.quotas {
+        let user = ctx
+            .read()
+            .await
+            .extra("user")
+            .unwrap_or_default()
+            .to_owned();
+        if let Err(e) = quotas.check(&user) {
+            info!("{}: {}", e.ctx, ctx.to_string().await);
+            return ctx.on_error(e).await;
+        }
+    }
     let connector = {
         let ctx = &ctx.clone().read_owned().await;
         state.rules().await.iter().find_map(|x| {
@@ -221,7 +295,12 @@ async fn process_request(ctx: ContextRef, state: Arc<GlobalState>) {
         .set_state(ContextState::ServerConnecting)
         .set_connector(connector.name().to_owned());
     let props = ctx.read().await.props().clone();
-    if let Err(e) = connector.connect(state.clone(), ctx.clone()).await {
+    let cancel = ctx.read().await.cancellation();
+    let ret = tokio::select! {
+        ret = connectors::connect(connector.clone(), state.clone(), ctx.clone()) => ret,
+        _ = cancel.cancelled() => Err(ctx.read().await.cancelled_error()),
+    };
+    if let Err(e) = ret {
         warn!(
             "failed to connect to upstream: {} cause: {:?} \nctx: {}",
             e,
@@ -232,7 +311,7 @@ async fn process_request(ctx: ContextRef, state: Arc<GlobalState>) {
     }
 
     ctx.on_connect().await;
-    if let Err(e) = copy_bidi(ctx.clone(), &state.io_params).await {
+    if let Err(e) = copy_bidi(ctx.clone(), &state.io_params, &state.bandwidth).await {
         warn!(
             "error in io thread: {} \ncause: {:?} \nctx: {}",
             e,
@@ -1,16 +1,24 @@
-use crate::{rules::Rule, GlobalState, VERSION};
+use crate::{
+    context::{ContextEvent, ContextEventKind, ContextProps},
+    rules::{filter::Filter, Rule},
+    GlobalState, VERSION,
+};
 use axum::{
     body::{Body, BoxBody},
-    extract::Extension,
+    extract::{Extension, Path, Query},
     http::{
-        header::{ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE},
+        header::{
+            HeaderName, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, CACHE_CONTROL,
+            CONTENT_TYPE,
+        },
         HeaderValue, Response, StatusCode,
     },
+    response::sse::{Event, KeepAlive, Sse},
     response::IntoResponse,
-    routing::{get, get_service, post},
+    routing::{delete, get, get_service, post},
     Json, Router,
 };
-use easy_error::{ensure, Error};
+use easy_error::{ensure, err_msg, Error, ResultExt};
 use futures::StreamExt;
 use prometheus::{
     register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
@@ -18,9 +26,12 @@ use prometheus::{
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     sync::{Arc, Weak},
+    time::{SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::{
     add_extension::AddExtensionLayer, services::ServeDir, set_header::SetResponseHeaderLayer,
     trace::TraceLayer,
@@ -82,15 +93,24 @@ impl MetricsServer {
     pub async fn listen(self: Arc<Self>, state: Arc<GlobalState>) -> Result<(), Error> {
         let api = Router::new()
             .route("/status", get(get_status))
-            .route("/live", get(get_alive))
+            .route("/live", get(get_alive).delete(delete_alive))
+            .route("/live/:id", delete(delete_alive_id))
             .route("/history", get(get_history))
+            .route("/events", get(get_events))
             .route("/rules", get(get_rules).post(post_rules))
             .route("/metrics", get(get_metrics))
             .route("/logrotate", post(post_logrotate))
+            .route("/reload", post(post_reload))
+            .route("/quotas", get(get_quotas))
+            .route("/quotas/:user", delete(delete_quota))
             .layer(AddExtensionLayer::new(state))
             .layer(SetResponseHeaderLayer::if_not_present(
                 CACHE_CONTROL,
                 HeaderValue::from_static("no-store"),
+            ))
+            .layer(SetResponseHeaderLayer::if_not_present(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(TOTAL_COUNT),
             ));
 
         let root = ui_service(self.ui.as_deref())?
@@ -179,19 +199,129 @@ handler!(get_status(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
         version: String,
         listeners: Vec<String>,
         connectors: Vec<String>,
+        // health of load balanced members, grouped by load balancer
+        health: HashMap<String, HashMap<String, bool>>,
     }
+    let connectors = state.connectors.read().await;
     Json(
         Status {
             version: VERSION.to_string(),
-            listeners: state.listeners.keys().cloned().collect(),
-            connectors: state.connectors.keys().cloned().collect(),
+            listeners: state.listeners.read().await.keys().cloned().collect(),
+            connectors: connectors.keys().cloned().collect(),
+            health: connectors
+                .values()
+                .filter_map(|c| Some((c.name().to_owned(), c.health()?)))
+                .collect(),
         }
     )
 });
 
-handler!(get_alive(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
-    Json(
-        futures::stream::iter(
+// number of matching contexts before pagination
+const TOTAL_COUNT: &str = "x-total-count";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ListQuery {
+    filter: Option<String>,
+    // id, bytes, start or duration, prefix with "-" for descending order
+    sort: Option<String>,
+    offset: usize,
+    limit: Option<usize>,
+    // comma separated top level fields to return, all if omitted
+    fields: Option<String>,
+}
+
+impl ListQuery {
+    // `now` is the end time of contexts still running, history uses their last state
+    fn apply(
+        &self,
+        mut list: Vec<Arc<ContextProps>>,
+        now: Option<SystemTime>,
+    ) -> Result<(usize, Vec<Arc<ContextProps>>), MyError> {
+        if let Some(filter) = &self.filter {
+            let filter = parse_filter(filter)?;
+            list.retain(|x| filter.evaluate_props(x).unwrap_or(false));
+        }
+        // pages of alive contexts, which are kept in a hash map, need a stable order
+        let paged = self.offset > 0 || self.limit.is_some();
+        let sort = self
+            .sort
+            .as_deref()
+            .or(if paged { Some("id") } else { None });
+        if let Some(sort) = sort {
+            let (key, desc) = match sort.strip_prefix('-') {
+                Some(key) => (key, true),
+                None => (sort, false),
+            };
+            let millis =
+                |end: SystemTime, start| end.duration_since(start).unwrap_or_default().as_millis();
+            let key: Box<dyn Fn(&ContextProps) -> u128> = match key {
+                "id" => Box::new(|x| x.id as u128),
+                "bytes" => {
+                    Box::new(|x| (x.client_stat.read_bytes() + x.server_stat.read_bytes()) as u128)
+                }
+                "start" => Box::new(move |x| millis(x.start_time(), UNIX_EPOCH)),
+                "duration" => Box::new(move |x| {
+                    let end = now.unwrap_or_else(|| x.last_update());
+                    millis(end, x.start_time())
+                }),
+                _ => {
+                    return Err(MyError::BadRequest(err_msg(format!(
+                        "unknown sort key: {}",
+                        key
+                    ))))
+                }
+            };
+            list.sort_by_cached_key(|x| key(x));
+            if desc {
+                list.reverse();
+            }
+        }
+        let total = list.len();
+        let list = list
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok((total, list))
+    }
+
+    fn respond(
+        &self,
+        list: Vec<Arc<ContextProps>>,
+        now: Option<SystemTime>,
+    ) -> Result<Response<BoxBody>, MyError> {
+        let (total, list) = self.apply(list, now)?;
+        let mut ret = match &self.fields {
+            None => Json(list).into_response(),
+            Some(fields) => {
+                let fields: Vec<_> = fields.split(',').map(str::trim).collect();
+                let list = list
+                    .iter()
+                    .map(|x| {
+                        let mut value = serde_json::to_value(x)?;
+                        if let serde_json::Value::Object(map) = &mut value {
+                            map.retain(|k, _| fields.contains(&k.as_str()));
+                        }
+                        Ok(value)
+                    })
+                    .collect::<Result<Vec<_>, serde_json::Error>>()
+                    .context("serialize")
+                    .map_err(MyError::Internal)?;
+                Json(list).into_response()
+            }
+        };
+        ret.headers_mut()
+            .insert(HeaderName::from_static(TOTAL_COUNT), total.into());
+        Ok(ret)
+    }
+}
+
+handler!(get_alive(
+    state: Extension<Arc<GlobalState>>,
+    query: Query<ListQuery>
+) -> Result<impl IntoResponse, MyError> {
+    let list = futures::stream::iter(
             state
                 .contexts
                 .alive
@@ -202,21 +332,141 @@ handler!(get_alive(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
         )
         .then(|x| async move { x.read().await.props().clone() })
         .collect::<Vec<_>>()
-        .await,
-    )
+        .await;
+    query.respond(list, Some(SystemTime::now()))
 });
 
-handler!(get_history(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
-    Json(
-        state
-            .contexts
-            .terminated
-            .lock()
-            .await
-            .iter()
-            .cloned()
-            .collect::<Vec<_>>(),
-    )
+// reason given to contexts cancelled through api
+const TERMINATED_BY_ADMIN: &str = "terminated by admin";
+
+handler!(delete_alive_id(
+    state: Extension<Arc<GlobalState>>,
+    id: Path<u64>
+) -> impl IntoResponse {
+    let ctx = state.contexts.alive.lock().await.get(&id).and_then(Weak::upgrade);
+    if let Some(ctx) = ctx {
+        ctx.write().await.cancel(TERMINATED_BY_ADMIN);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+});
+
+#[derive(Deserialize)]
+struct FilterQuery {
+    filter: String,
+}
+
+fn parse_filter(s: &str) -> Result<Filter, MyError> {
+    let filter: Filter = s
+        .parse()
+        .context("parse filter")
+        .map_err(MyError::BadRequest)?;
+    filter.validate().map_err(MyError::BadRequest)?;
+    Ok(filter)
+}
+
+// cancels all alive contexts matching filter, returns their ids
+handler!(delete_alive(
+    state: Extension<Arc<GlobalState>>,
+    query: Query<FilterQuery>
+) -> Result<impl IntoResponse, MyError> {
+    let filter = parse_filter(&query.filter)?;
+    let mut ids = vec![];
+    for ctx in state.contexts.alive_contexts().await {
+        if filter.evaluate(&*ctx.read().await).unwrap_or(false) {
+            let mut ctx = ctx.write().await;
+            ctx.cancel(TERMINATED_BY_ADMIN);
+            ids.push(ctx.props().id);
+        }
+    }
+    Ok(Json(ids))
+});
+
+handler!(get_history(
+    state: Extension<Arc<GlobalState>>,
+    query: Query<ListQuery>
+) -> Result<impl IntoResponse, MyError> {
+    let list = state
+        .contexts
+        .terminated
+        .lock()
+        .await
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    query.respond(list, None)
+});
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    filter: Option<String>,
+}
+
+// user, target and sniffed host of a context are unknown when it is created,
+// so filters are evaluated on every event: a context is announced as created
+// on the first event it matches, and followed until it terminates
+struct EventFilter {
+    filter: Option<Filter>,
+    // ids of contexts announced to subscriber
+    matched: HashSet<u64>,
+}
+
+impl EventFilter {
+    // name of the event sent to subscriber, None if it is filtered out
+    fn select(&mut self, event: &ContextEvent) -> Option<&'static str> {
+        let filter = match &self.filter {
+            Some(x) => x,
+            None => return Some(event.kind.as_str()),
+        };
+        let id = event.props.id;
+        let matches = || filter.evaluate_props(&event.props).unwrap_or(false);
+        if event.kind == ContextEventKind::Terminated {
+            return (self.matched.remove(&id) || matches()).then(|| event.kind.as_str());
+        }
+        if self.matched.contains(&id) {
+            Some(event.kind.as_str())
+        } else if matches() {
+            self.matched.insert(id);
+            Some(ContextEventKind::Created.as_str())
+        } else {
+            None
+        }
+    }
+}
+
+// streams lifecycle events of contexts matching filter as server-sent events,
+// named after the event kind with context props as data
+handler!(get_events(
+    state: Extension<Arc<GlobalState>>,
+    query: Query<EventsQuery>
+) -> Result<impl IntoResponse, MyError> {
+    let filter = EventFilter {
+        filter: query.filter.as_deref().map(parse_filter).transpose()?,
+        matched: HashSet::new(),
+    };
+    let rx = state
+        .contexts
+        .subscribe()
+        .ok_or_else(|| MyError::BadRequest(err_msg("events not enabled")))?;
+    let stream = futures::stream::unfold((rx, filter), |(mut rx, mut filter)| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(x) => x,
+                // tells client to reload, some events are lost
+                Err(RecvError::Lagged(n)) => {
+                    let ret = Event::default().event("lagged").data(n.to_string());
+                    return Some((Ok(ret), (rx, filter)));
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            if let Some(kind) = filter.select(&event) {
+                let ret = Event::default().event(kind).json_data(&*event.props);
+                return Some((ret, (rx, filter)));
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 });
 
 handler!(get_rules(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
@@ -227,7 +477,7 @@ handler!(post_rules(
     state: Extension<Arc<GlobalState>>,
     rules: Json<Vec<Arc<Rule>>>
 ) -> Result<impl IntoResponse, MyError> {
-    state.set_rules(rules.0).await.map_err(MyError)?;
+    state.set_rules(rules.0).await.map_err(MyError::Internal)?;
     Ok(Json(state.rules().await.clone()))
 });
 
@@ -245,19 +495,45 @@ handler!(get_metrics() -> impl IntoResponse {
 
 handler!(post_logrotate(state: Extension<Arc<GlobalState>>) -> Result<(), MyError> {
     if let Some(log) = &state.contexts.access_log {
-        log.reopen().await.map_err(MyError)
+        log.reopen().await.map_err(MyError::Internal)
     } else {
         Ok(())
     }
 });
 
-struct MyError(Error);
+handler!(post_reload(state: Extension<Arc<GlobalState>>) -> Result<impl IntoResponse, MyError> {
+    Ok(Json(state.reload().await.map_err(MyError::Internal)?))
+});
+
+handler!(get_quotas(state: Extension<Arc<GlobalState>>) -> impl IntoResponse {
+    Json(state.contexts.quotas.as_ref().map(|q| q.report()).unwrap_or_default())
+});
+
+handler!(delete_quota(
+    state: Extension<Arc<GlobalState>>,
+    user: Path<String>
+) -> impl IntoResponse {
+    match &state.contexts.quotas {
+        Some(quotas) if quotas.reset(&user) => StatusCode::NO_CONTENT,
+        _ => StatusCode::NOT_FOUND,
+    }
+});
+
+enum MyError {
+    Internal(Error),
+    // malformed parameters sent by client
+    BadRequest(Error),
+}
 
 impl IntoResponse for MyError {
     fn into_response(self) -> Response<BoxBody> {
-        let body = Body::from(format!("{} cause: {:?}", self.0, self.0.cause));
+        let (status, e) = match self {
+            MyError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            MyError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
+        };
+        let body = Body::from(format!("{} cause: {:?}", e, e.cause));
         Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .status(status)
             .body(body)
             .unwrap()
             .into_response()
@@ -268,3 +544,124 @@ impl IntoResponse for MyError {
 mod embedded_ui {
     include!(concat!(env!("OUT_DIR"), "/embedded-ui.rs"));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextState;
+    use std::time::Duration;
+
+    fn props(id: u64, listener: &str, bytes: usize, start: u64, end: u64) -> Arc<ContextProps> {
+        let time = |x| UNIX_EPOCH + Duration::from_secs(x);
+        let props = ContextProps {
+            id,
+            listener: listener.into(),
+            state: vec![
+                (ContextState::ClientConnected, time(start)).into(),
+                (ContextState::Terminated, time(end)).into(),
+            ],
+            ..Default::default()
+        };
+        props.client_stat.incr_sent_bytes(bytes);
+        Arc::new(props)
+    }
+
+    fn ids(query: &str, now: Option<SystemTime>) -> (usize, Vec<u64>) {
+        let list = vec![
+            props(2, "http", 200, 30, 31),
+            props(0, "http", 300, 10, 20),
+            props(1, "socks", 100, 5, 50),
+        ];
+        let uri = format!("/?{}", query).parse().unwrap();
+        let (total, list) = Query::<ListQuery>::try_from_uri(&uri)
+            .unwrap()
+            .apply(list, now)
+            .unwrap_or_else(|_| panic!("bad query: {}", query));
+        (total, list.iter().map(|x| x.id).collect())
+    }
+
+    #[test]
+    fn list_query() {
+        assert_eq!(ids("", None), (3, vec![2, 0, 1]));
+        assert_eq!(ids("sort=bytes", None), (3, vec![1, 2, 0]));
+        assert_eq!(ids("sort=-start", None), (3, vec![2, 0, 1]));
+        assert_eq!(ids("sort=-duration", None), (3, vec![1, 0, 2]));
+        // running contexts are measured until now
+        let now = Some(UNIX_EPOCH + Duration::from_secs(100));
+        assert_eq!(ids("sort=duration", now), (3, vec![2, 0, 1]));
+        assert_eq!(
+            ids(
+                "filter=request.listener%20%3D%3D%20%22http%22&sort=-id",
+                None
+            ),
+            (2, vec![2, 0])
+        );
+        assert_eq!(ids("sort=id&offset=1&limit=1", None), (3, vec![1]));
+        assert_eq!(ids("offset=5", None), (3, vec![]));
+        // paged without sort key, ordered by id
+        assert_eq!(ids("limit=2", None), (3, vec![0, 1]));
+
+        let query = ListQuery {
+            sort: Some("size".into()),
+            ..Default::default()
+        };
+        let status = query
+            .apply(vec![], None)
+            .err()
+            .unwrap()
+            .into_response()
+            .status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let query = ListQuery {
+            filter: Some("request.listener ==".into()),
+            ..Default::default()
+        };
+        let status = query
+            .apply(vec![], None)
+            .err()
+            .unwrap()
+            .into_response()
+            .status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn event_filter() {
+        let mut filter = EventFilter {
+            filter: Some(parse_filter(r#"request.user == "alice""#).ok().unwrap()),
+            matched: HashSet::new(),
+        };
+        let mut select = |id, user: &str, kind| {
+            let mut props = ContextProps {
+                id,
+                ..Default::default()
+            };
+            if !user.is_empty() {
+                props.extra.insert("user".into(), user.into());
+            }
+            filter.select(&ContextEvent {
+                kind,
+                props: Arc::new(props),
+            })
+        };
+        use ContextEventKind::*;
+        // user is known after creation, announced when it shows up
+        assert_eq!(select(1, "", Created), None);
+        assert_eq!(select(1, "alice", StateChanged), Some("created"));
+        assert_eq!(select(1, "alice", ConnectorSelected), Some("connector"));
+        assert_eq!(select(1, "alice", Terminated), Some("terminated"));
+        assert_eq!(select(2, "", Created), None);
+        assert_eq!(select(2, "bob", StateChanged), None);
+        assert_eq!(select(2, "bob", Terminated), None);
+
+        let mut filter = EventFilter {
+            filter: None,
+            matched: HashSet::new(),
+        };
+        let event = ContextEvent {
+            kind: StateChanged,
+            props: Default::default(),
+        };
+        assert_eq!(filter.select(&event), Some("state"));
+    }
+}
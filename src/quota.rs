@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use easy_error::{bail, ensure, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::context::{ContextProps, GlobalState as ContextGlobalState};
+
+// how often usage of running contexts is collected
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Traffic accounting and quotas of authenticated users.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Quotas {
+    // json file keeping usage across restarts
+    path: Option<PathBuf>,
+    // unit: seconds
+    #[serde(default = "default_save_interval")]
+    save_interval: u64,
+    // also cancel running contexts of users exceeding their quota
+    #[serde(default)]
+    terminate: bool,
+    // quota of users not listed below
+    default: Option<Quota>,
+    #[serde(default)]
+    users: HashMap<String, Quota>,
+    #[serde(skip)]
+    usage: StdMutex<HashMap<String, Usage>>,
+    #[serde(skip)]
+    live: StdMutex<Live>,
+}
+
+#[derive(Debug, Default)]
+struct Live {
+    // user and bytes of running contexts as of the last collect, by context id
+    contexts: HashMap<u64, (String, u64)>,
+    // contexts recorded since the last collect started, which may still be
+    // in the snapshot it is taking
+    recorded: HashSet<u64>,
+}
+
+impl Live {
+    fn user(&self, user: &str) -> u64 {
+        self.contexts
+            .values()
+            .filter(|(x, _)| x == user)
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+}
+
+fn default_save_interval() -> u64 {
+    60
+}
+
+/// Bytes a user may transfer in either direction, windows are in UTC.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    // days and months since unix epoch of the windows below
+    day: u64,
+    month: u64,
+    daily: Counter,
+    monthly: Counter,
+    total: Counter,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Counter {
+    bytes: u64,
+    connections: u64,
+}
+
+impl Counter {
+    fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.connections += 1;
+    }
+}
+
+impl Usage {
+    // starts new windows once the old ones are over
+    fn roll(&mut self, (day, month): (u64, u64)) {
+        if self.day != day {
+            self.day = day;
+            self.daily = Default::default();
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = Default::default();
+        }
+    }
+}
+
+/// Usage of a user as reported by the api.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserReport {
+    quota: Option<Quota>,
+    // bytes of contexts still running, not yet in usage
+    live: u64,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+impl Quotas {
+    pub async fn init(&mut self) -> Result<(), Error> {
+        ensure!(
+            self.save_interval > 0,
+            "saveInterval must greater than zero"
+        );
+        let path = match &self.path {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        match tokio::fs::read(path).await {
+            Ok(data) => {
+                let usage = serde_json::from_slice(&data)
+                    .with_context(|| format!("parse {}", path.display()))?;
+                *self.usage.get_mut().unwrap() = usage;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        }
+        Ok(())
+    }
+
+    fn quota(&self, user: &str) -> Option<Quota> {
+        self.users.get(user).copied().or(self.default)
+    }
+
+    /// Adds traffic of a finished context to its user.
+    pub fn record(&self, props: &ContextProps) {
+        // counted as usage from now on
+        let mut live = self.live.lock().unwrap();
+        live.contexts.remove(&props.id);
+        live.recorded.insert(props.id);
+        drop(live);
+        let user = match props.extra.get("user").filter(|x| !x.is_empty()) {
+            Some(x) => x,
+            None => return,
+        };
+        // denied or rejected before connecting
+        if !props.connected() {
+            return;
+        }
+        let bytes = (props.client_stat.read_bytes() + props.server_stat.read_bytes()) as u64;
+        self.add(user, bytes, window(SystemTime::now()));
+    }
+
+    fn add(&self, user: &str, bytes: u64, window: (u64, u64)) {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(user.to_owned()).or_default();
+        usage.roll(window);
+        usage.daily.add(bytes);
+        usage.monthly.add(bytes);
+        usage.total.add(bytes);
+    }
+
+    /// Fails if `user` has used up a quota.
+    pub fn check(&self, user: &str) -> Result<(), Error> {
+        self.check_at(user, window(SystemTime::now()))
+    }
+
+    fn check_at(&self, user: &str, window: (u64, u64)) -> Result<(), Error> {
+        let quota = match self.quota(user) {
+            Some(x) if !user.is_empty() => x,
+            _ => return Ok(()),
+        };
+        let live = self.live.lock().unwrap().user(user);
+        let mut usage = self
+            .usage
+            .lock()
+            .unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default();
+        usage.roll(window);
+        if let Some(daily) = quota.daily {
+            if usage.daily.bytes + live >= daily {
+                bail!("daily quota exceeded");
+            }
+        }
+        if let Some(monthly) = quota.monthly {
+            if usage.monthly.bytes + live >= monthly {
+                bail!("monthly quota exceeded");
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn report(&self) -> HashMap<String, UserReport> {
+        let now = window(SystemTime::now());
+        let mut live = HashMap::new();
+        for (user, bytes) in self.live.lock().unwrap().contexts.values() {
+            *live.entry(user.clone()).or_insert(0) += bytes;
+        }
+        let mut usage = self.usage.lock().unwrap().clone();
+        for user in live.keys() {
+            usage.entry(user.clone()).or_default();
+        }
+        usage
+            .into_iter()
+            .map(|(user, mut usage)| {
+                usage.roll(now);
+                let report = UserReport {
+                    quota: self.quota(&user),
+                    live: live.get(&user).copied().unwrap_or(0),
+                    usage,
+                };
+                (user, report)
+            })
+            .collect()
+    }
+
+    /// Forgets usage of `user`, returns false if there was none.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn reset(&self, user: &str) -> bool {
+        self.usage.lock().unwrap().remove(user).is_some()
+    }
+
+    pub async fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let data = serde_json::to_vec(&*self.usage.lock().unwrap()).context("serialize")?;
+        // write then rename, so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data)
+            .await
+            .with_context(|| format!("write {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("rename {}", tmp.display()))
+    }
+
+    /// Periodically collects usage of running contexts, cancels them if
+    /// configured to, and saves usage to file.
+    pub fn start(self: Arc<Self>, contexts: Arc<ContextGlobalState>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            let mut last_save = tokio::time::Instant::now();
+            loop {
+                interval.tick().await;
+                self.collect(&contexts).await;
+                if last_save.elapsed() >= Duration::from_secs(self.save_interval) {
+                    last_save = tokio::time::Instant::now();
+                    if let Err(e) = self.save().await {
+                        warn!("failed to save quota usage: {} cause: {:?}", e, e.cause);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn collect(&self, contexts: &ContextGlobalState) {
+        self.live.lock().unwrap().recorded.clear();
+        let mut snapshot = HashMap::new();
+        let mut running = vec![];
+        for ctx in contexts.alive_contexts().await {
+            let props = ctx.read().await.props().clone();
+            if let Some(user) = props.extra.get("user").filter(|x| !x.is_empty()) {
+                let bytes = props.client_stat.read_bytes() + props.server_stat.read_bytes();
+                snapshot.insert(props.id, (user.clone(), bytes as u64));
+                running.push((user.clone(), ctx));
+            }
+        }
+        {
+            let mut live = self.live.lock().unwrap();
+            snapshot.retain(|id, _| !live.recorded.contains(id));
+            live.contexts = snapshot;
+        }
+        if !self.terminate {
+            return;
+        }
+        for (user, ctx) in running {
+            if let Err(e) = self.check(&user) {
+                info!("terminating context of {}: {}", user, e.ctx);
+                ctx.write().await.cancel(e.ctx);
+            }
+        }
+    }
+}
+
+// (day, month) since unix epoch in UTC
+fn window(now: SystemTime) -> (u64, u64) {
+    let days = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+    (days, months(days))
+}
+
+// converts days since epoch to months since epoch, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn months(days: u64) -> u64 {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let (year, month) = if mp < 10 {
+        (yoe + era * 400, mp + 2)
+    } else {
+        (yoe + era * 400 + 1, mp - 10)
+    };
+    (year - 1970) * 12 + month
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_months() {
+        assert_eq!(months(0), 0);
+        assert_eq!(months(30), 0);
+        assert_eq!(months(31), 1);
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(months(19782), 54 * 12 + 1);
+        assert_eq!(months(19783), 54 * 12 + 2);
+        // 2023-12-31 and 2024-01-01
+        assert_eq!(months(19722), 53 * 12 + 11);
+        assert_eq!(months(19723), 54 * 12);
+    }
+
+    #[test]
+    fn quotas() {
+        let q: Quotas = serde_yaml::from_str(
+            "{default: {daily: 100}, users: {alice: {daily: 1000, monthly: 1500}}}",
+        )
+        .unwrap();
+        let day1 = (19723, 648);
+        q.add("alice", 600, day1);
+        q.add("bob", 100, day1);
+        assert!(q.check_at("alice", day1).is_ok());
+        assert_eq!(
+            q.check_at("bob", day1).unwrap_err().ctx,
+            "daily quota exceeded"
+        );
+        // anonymous users are never limited
+        assert!(q.check_at("", day1).is_ok());
+
+        q.add("alice", 400, day1);
+        assert_eq!(
+            q.check_at("alice", day1).unwrap_err().ctx,
+            "daily quota exceeded"
+        );
+        let day2 = (19724, 648);
+        assert!(q.check_at("bob", day2).is_ok());
+        q.add("alice", 500, day2);
+        assert_eq!(
+            q.check_at("alice", day2).unwrap_err().ctx,
+            "monthly quota exceeded"
+        );
+        let usage = q.usage.lock().unwrap()["alice"].clone();
+        assert_eq!(usage.daily.bytes, 500);
+        assert_eq!(usage.total.connections, 3);
+
+        assert!(q.reset("alice"));
+        assert!(q.check_at("alice", day2).is_ok());
+    }
+
+    #[test]
+    fn record() {
+        use crate::context::ContextState;
+        let q: Quotas = serde_yaml::from_str("{default: {daily: 100}}").unwrap();
+        let props = |id, state| {
+            let props = ContextProps {
+                id,
+                state: vec![
+                    (ContextState::ClientConnected, SystemTime::now()).into(),
+                    (state, SystemTime::now()).into(),
+                ],
+                extra: [("user".to_owned(), "alice".to_owned())].into(),
+                ..Default::default()
+            };
+            props.client_stat.incr_sent_bytes(80);
+            props
+        };
+
+        // denied by rules, quota or connection limits
+        q.record(&props(1, ContextState::ErrorOccured));
+        q.record(&props(2, ContextState::Rejected));
+        assert!(q.usage.lock().unwrap().get("alice").is_none());
+
+        // snapshot of a running context, then it finishes
+        q.live
+            .lock()
+            .unwrap()
+            .contexts
+            .insert(3, ("alice".to_owned(), 60));
+        assert_eq!(q.report()["alice"].live, 60);
+        q.record(&props(3, ContextState::Connected));
+        let report = &q.report()["alice"];
+        assert_eq!(report.live, 0);
+        assert_eq!(report.usage.daily.bytes, 80);
+        assert_eq!(report.usage.daily.connections, 1);
+        assert!(q.check("alice").is_ok());
+    }
+}
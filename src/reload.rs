@@ -0,0 +1,260 @@
+use std::{collections::HashMap, sync::Arc};
+
+use easy_error::{Error, ResultExt};
+use serde::Serialize;
+use serde_yaml::Value;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::Config, connectors, link_rules, listeners, rules, GlobalState};
+
+// these sections are consumed once at startup
+const RESTART_REQUIRED: &[&str] = &[
+    "timeouts",
+    "ioParams",
+    "bandwidth",
+    "quotas",
+    "connectionLimits",
+    "metrics",
+    "accessLog",
+];
+
+/// Names of listeners or connectors touched by a reload.
+#[derive(Serialize, Debug, Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl Changes {
+    fn new(old: &HashMap<String, Value>, new: &HashMap<String, Value>) -> Self {
+        let mut ret = Self::default();
+        for (name, value) in new {
+            match old.get(name) {
+                None => ret.added.push(name.to_owned()),
+                Some(old) if old != value => ret.changed.push(name.to_owned()),
+                _ => {}
+            }
+        }
+        ret.removed = old
+            .keys()
+            .filter(|name| !new.contains_key(*name))
+            .cloned()
+            .collect();
+        ret.added.sort();
+        ret.removed.sort();
+        ret.changed.sort();
+        ret
+    }
+
+    fn unchanged(&self, name: &str) -> bool {
+        !self.added.iter().chain(&self.changed).any(|x| x == name)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReloadSummary {
+    pub listeners: Changes,
+    pub connectors: Changes,
+}
+
+// name => definition of every entry in a listeners or connectors section
+fn definitions(section: Option<&Value>) -> HashMap<String, Value> {
+    section
+        .and_then(Value::as_sequence)
+        .map(|seq| {
+            seq.iter()
+                .filter_map(|v| {
+                    let name = v.get("name").and_then(Value::as_str)?;
+                    Some((name.to_owned(), v.clone()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub async fn stop_listener(name: &str, task: JoinHandle<()>) {
+    task.abort();
+    // wait for the task to be dropped so the listening socket gets closed
+    let _ = task.await;
+    info!("{}: listener stopped", name);
+}
+
+impl GlobalState {
+    /// Re-reads the config file and applies listeners, connectors and rules.
+    ///
+    /// Unchanged listeners and connectors are kept as is, existing contexts
+    /// keep the connectors they are using. On any error the running config
+    /// stays untouched.
+    pub async fn reload(self: &Arc<Self>) -> Result<ReloadSummary, Error> {
+        let mut current = self.config.lock().await;
+        let raw = Config::load_value(&self.config_path).await?;
+        let cfg = Config::from_value(raw.clone())?;
+        for key in RESTART_REQUIRED {
+            if raw.get(key) != current.get(key) {
+                warn!("{} changed, restart required to take effect", key);
+            }
+        }
+
+        let old_connectors = self.connectors.read().await.clone();
+        let connector_changes = Changes::new(
+            &definitions(current.get("connectors")),
+            &definitions(raw.get("connectors")),
+        );
+        let mut connectors = connectors::from_config(&cfg.connectors)?;
+        for (name, c) in connectors.iter_mut() {
+            match old_connectors.get(name) {
+                Some(old) if connector_changes.unchanged(name) => *c = old.clone(),
+                _ => Arc::get_mut(c)
+                    .unwrap()
+                    .init()
+                    .await
+                    .with_context(|| format!("init connector {}", name))?,
+            }
+        }
+        for c in connectors.values() {
+            c.verify(&connectors)
+                .await
+                .with_context(|| format!("verify connector {}", c.name()))?;
+        }
+
+        let mut rules = rules::from_config(&cfg.rules)?;
+        link_rules(&mut rules, &connectors)?;
+
+        let old_listeners = self.listeners.read().await.clone();
+        let listener_changes = Changes::new(
+            &definitions(current.get("listeners")),
+            &definitions(raw.get("listeners")),
+        );
+        let mut listeners = listeners::from_config(&cfg.listeners)?;
+        for (name, l) in listeners.iter_mut() {
+            match old_listeners.get(name) {
+                Some(old) if listener_changes.unchanged(name) => *l = old.clone(),
+                _ => {
+                    Arc::get_mut(l)
+                        .unwrap()
+                        .init()
+                        .await
+                        .with_context(|| format!("init listener {}", name))?;
+                    l.verify(self.clone())
+                        .await
+                        .with_context(|| format!("verify listener {}", name))?;
+                }
+            }
+        }
+
+        // old listeners must be stopped first, a changed one may reuse its address
+        let queue = self.queue.clone().unwrap();
+        let mut tasks = self.listener_tasks.lock().await;
+        let mut stopped = Vec::new();
+        for name in listener_changes
+            .removed
+            .iter()
+            .chain(&listener_changes.changed)
+        {
+            if let Some(task) = tasks.remove(name) {
+                stop_listener(name, task).await;
+            }
+            stopped.push(old_listeners[name].clone());
+        }
+        let mut started = Vec::new();
+        for name in listener_changes
+            .added
+            .iter()
+            .chain(&listener_changes.changed)
+        {
+            let l = listeners[name].clone();
+            match l.listen(self.clone(), queue.clone()).await {
+                Ok(task) => started.push((name.to_owned(), task)),
+                Err(e) => {
+                    for (name, task) in started {
+                        stop_listener(&name, task).await;
+                    }
+                    for l in stopped {
+                        let name = l.name().to_owned();
+                        match l.listen(self.clone(), queue.clone()).await {
+                            Ok(task) => {
+                                tasks.insert(name, task);
+                            }
+                            Err(e) => error!(
+                                "{}: failed to restore listener: {} cause: {:?}",
+                                name, e, e.cause
+                            ),
+                        }
+                    }
+                    return Err(e).with_context(|| format!("start listener {}", name));
+                }
+            }
+        }
+        tasks.extend(started);
+        drop(tasks);
+
+        *self.listeners.write().await = listeners;
+        {
+            // same lock order as set_rules
+            let mut old = self.connectors.write().await;
+            *old = connectors;
+            *self.rules.write().await = rules;
+            for name in connector_changes
+                .added
+                .iter()
+                .chain(&connector_changes.changed)
+            {
+                old[name].clone().start(self.clone());
+            }
+        }
+        *current = raw;
+
+        let ret = ReloadSummary {
+            listeners: listener_changes,
+            connectors: connector_changes,
+        };
+        info!("config reloaded: {:?}", ret);
+        Ok(ret)
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub async fn signal_watch(_state: Arc<GlobalState>) {}
+
+#[cfg(not(target_os = "windows"))]
+pub async fn signal_watch(state: Arc<GlobalState>) {
+    let mut stream = signal(SignalKind::hangup()).unwrap();
+    while stream.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        if let Err(e) = state.reload().await {
+            error!("failed to reload config: {} cause: {:?}", e, e.cause);
+        }
+    }
+    error!("signal watch ends");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(s: &str) -> HashMap<String, Value> {
+        definitions(Some(&serde_yaml::from_str(s).unwrap()))
+    }
+
+    #[test]
+    fn changes() {
+        let old = section(
+            "[{name: a, bind: '0.0.0.0:1'}, {name: b, bind: '0.0.0.0:2'}, {name: c, bind: '0.0.0.0:3'}]",
+        );
+        let new = section(
+            "[{name: a, bind: '0.0.0.0:1'}, {name: c, bind: '0.0.0.0:4'}, {name: d, bind: '0.0.0.0:5'}]",
+        );
+        let changes = Changes::new(&old, &new);
+        assert_eq!(changes.added, vec!["d"]);
+        assert_eq!(changes.removed, vec!["b"]);
+        assert_eq!(changes.changed, vec!["c"]);
+        assert!(changes.unchanged("a"));
+        assert!(!changes.unchanged("c"));
+        assert!(!changes.unchanged("d"));
+    }
+}
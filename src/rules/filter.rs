@@ -5,8 +5,9 @@ use std::convert::TryInto;
 use std::str::FromStr;
 use tracing::trace;
 
-use crate::context::Context;
+use crate::context::{Context, ContextProps};
 use crate::rules::script_ext::create_context;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Filter {
@@ -25,9 +26,12 @@ impl Filter {
         Ok(())
     }
     pub fn evaluate(&self, request: &Context) -> Result<bool, Error> {
-        let ctx = create_context(request.props().clone());
+        self.evaluate_props(request.props())
+    }
+    pub fn evaluate_props(&self, props: &Arc<ContextProps>) -> Result<bool, Error> {
+        let ctx = create_context(props.clone());
         let ret = self.root.value_of(ctx.into())?.try_into()?;
-        trace!("filter eval: {:?} => {}", request, ret);
+        trace!("filter eval: {} => {}", props, ret);
         Ok(ret)
     }
 }
@@ -1,6 +1,6 @@
 // Any sufficiently complicated C or Fortran program contains an ad hoc, informally-specified, bug-ridden, slow implementation of half of Common Lisp.  --Greenspun's tenth rule
 
-mod filter;
+pub(crate) mod filter;
 pub(crate) mod script_ext;
 use easy_error::{Error, ResultExt};
 use serde::{Deserialize, Serialize};
@@ -97,16 +97,16 @@ impl Rule {
             RULES_EXECUTE_TIME.start_timer()
         };
         let t = Instant::now();
-        let ret = if self.filter.is_none() {
-            true
-        } else {
-            match self.filter.as_ref().unwrap().evaluate(request) {
+        let ret = if let Some(filter) = &self.filter {
+            match filter.evaluate(request) {
                 Ok(b) => b,
                 Err(e) => {
                     trace!("error evaluating filter: {:?}", e);
                     false
                 }
             }
+        } else {
+            true
         };
         let t = t.elapsed().as_nanos() as u64;
         #[cfg(feature = "metrics")]
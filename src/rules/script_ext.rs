@@ -33,7 +33,16 @@ impl ContextAdaptor {
 
 impl Accessible for ContextAdaptor {
     fn names(&self) -> Vec<&str> {
-        vec!["listener", "connector", "source", "target", "feature"]
+        vec![
+            "listener",
+            "connector",
+            "source",
+            "target",
+            "feature",
+            "user",
+            "sniffed",
+            "peer",
+        ]
     }
 
     fn get(&self, name: &str) -> Result<Value, Error> {
@@ -43,14 +52,31 @@ impl Accessible for ContextAdaptor {
             "target" => Ok(self.req.target.clone().into()),
             "source" => Ok(SocketAddress(self.req.source).into()),
             "feature" => Ok(self.req.request_feature.to_string().into()),
+            "user" => Ok(self
+                .req
+                .extra
+                .get("user")
+                .map(String::as_str)
+                .unwrap_or("")
+                .into()),
+            // host name sniffed by transparent listeners, empty if none
+            "sniffed" => Ok(self
+                .req
+                .extra
+                .get("sniffed-host")
+                .map(String::as_str)
+                .unwrap_or("")
+                .into()),
+            // credentials of unix socket clients
+            "peer" => Ok(PeerCredentials::from_props(&self.req).into()),
             _ => bail!("property undefined: {}", name),
         }
     }
 
     fn type_of(&self, name: &str, ctx: ScriptContextRef) -> Result<Type, Error> {
         match name {
-            "listener" | "connector" | "feature" => Ok(Type::String),
-            "target" | "source" => self.get(name)?.type_of(ctx),
+            "listener" | "connector" | "feature" | "user" | "sniffed" => Ok(Type::String),
+            "target" | "source" | "peer" => self.get(name)?.type_of(ctx),
             _ => bail!("undefined field: {}", name),
         }
     }
@@ -180,6 +206,59 @@ impl Accessible for SocketAddress {
     }
 }
 
+/// uid, gid and pid of peer, -1 if unknown.
+#[derive(Debug, Hash)]
+struct PeerCredentials {
+    uid: i64,
+    gid: i64,
+    pid: i64,
+}
+
+impl PeerCredentials {
+    fn from_props(props: &ContextProps) -> Self {
+        let get = |key| {
+            props
+                .extra
+                .get(key)
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(-1)
+        };
+        Self {
+            uid: get("peer-uid"),
+            gid: get("peer-gid"),
+            pid: get("peer-pid"),
+        }
+    }
+}
+
+impl NativeObject for PeerCredentials {
+    fn as_accessible(&self) -> Option<&dyn Accessible> {
+        Some(self)
+    }
+}
+
+impl Accessible for PeerCredentials {
+    fn names(&self) -> Vec<&str> {
+        vec!["uid", "gid", "pid"]
+    }
+
+    fn get(&self, name: &str) -> Result<Value, Error> {
+        match name {
+            "uid" => Ok(self.uid.into()),
+            "gid" => Ok(self.gid.into()),
+            "pid" => Ok(self.pid.into()),
+            _ => bail!("property undefined: {}", name),
+        }
+    }
+
+    fn type_of<'b>(&self, name: &str, _ctx: ScriptContextRef) -> Result<Type, Error> {
+        match name {
+            "uid" | "gid" | "pid" => Ok(Type::Integer),
+            _ => bail!("undefined"),
+        }
+    }
+}
+
 function!(CidrMatch(ip: String, cidr: String)=>Boolean, {
     let s_ip:String = ip.try_into()?;
     let s_cidr:String = cidr.try_into()?;
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use easy_error::{bail, Error};
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{info, warn};
+
+use crate::{reload::stop_listener, GlobalState};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// time given to cancelled contexts to report their errors
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+impl GlobalState {
+    /// Stops all listeners and waits for alive contexts to finish. Contexts
+    /// still running after `timeouts.shutdown` seconds are cancelled, in which
+    /// case an error is returned.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        // keep a reload from starting listeners again
+        let _config = self.config.lock().await;
+        let mut tasks = self.listener_tasks.lock().await;
+        for (name, task) in tasks.drain() {
+            stop_listener(&name, task).await;
+        }
+        drop(tasks);
+
+        let drain_timeout = Duration::from_secs(self.timeouts.shutdown);
+        info!(
+            "shutting down, waiting up to {:?} for {} contexts",
+            drain_timeout,
+            self.contexts.alive_contexts().await.len()
+        );
+        let mut left = self.wait_for_contexts(drain_timeout).await;
+        let cancelled = left;
+        if left > 0 {
+            warn!("drain timed out, cancelling {} contexts", left);
+            let cancel_all = async {
+                for ctx in self.contexts.alive_contexts().await {
+                    ctx.write().await.cancel("server shutting down");
+                }
+            };
+            if timeout(CANCEL_GRACE, cancel_all).await.is_ok() {
+                left = self.wait_for_contexts(CANCEL_GRACE).await;
+            }
+            if left > 0 {
+                warn!("{} contexts did not stop after cancellation", left);
+            }
+        }
+
+        self.contexts.gc().await;
+        if let Some(log) = &self.contexts.access_log {
+            log.flush().await?;
+        }
+        if let Some(quotas) = &self.contexts.quotas {
+            quotas.save().await?;
+        }
+        if cancelled > 0 {
+            bail!("shutdown timed out, {} contexts cancelled", cancelled);
+        }
+        info!("shutdown completed");
+        Ok(())
+    }
+
+    // returns the number of contexts still alive
+    async fn wait_for_contexts(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let alive = self.contexts.alive_contexts().await.len();
+            if alive == 0 || Instant::now() >= deadline {
+                return alive;
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub async fn signal() {
+    tokio::signal::ctrl_c().await.unwrap();
+    info!("ctrl-c received");
+}
+
+#[cfg(not(target_os = "windows"))]
+pub async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    info!("{} received", name);
+}
@@ -34,6 +34,16 @@ Date.prototype.timeSince = function () {
 }
 
 const API_PREFIX = "/api";
+// lifecycle events of contexts, see GET /api/events
+function subscribe(handlers) {
+  let source = new EventSource(API_PREFIX + '/events');
+  for (let name in handlers) {
+    source.addEventListener(name, (e) => handlers[name](e.data ? JSON.parse(e.data) : null));
+  }
+  source.onerror = (e) => console.log('Event stream error', e);
+  return source;
+}
+
 const TITLE_SURFIX = ' - redproxy-rs console';
 const app = Vue.createApp({
   data() {
@@ -108,8 +118,29 @@ app.component('tab-live', {
       list: []
     }
   },
-  mounted() { this.loadData(); },
+  mounted() {
+    let update = (item) => {
+      let i = this.list.findIndex((x) => x.id == item.id);
+      if (i < 0) {
+        this.list.push(item);
+      } else {
+        this.list[i] = item;
+      }
+    };
+    this.events = subscribe({
+      created: update,
+      state: update,
+      connector: update,
+      terminated: (item) => {
+        this.list = this.list.filter((x) => x.id != item.id);
+      },
+      lagged: () => this.loadData(),
+    });
+    this.loadData();
+  },
+  unmounted() { this.events.close(); },
   methods: {
+    // auto refresh still reloads the list, traffic counters are not streamed
     async loadData() {
       let response = await fetch(API_PREFIX + '/live');
       if (response.status !== 200) {
@@ -131,12 +162,25 @@ app.component('tab-history', {
   </div>`,
   data() {
     return {
-      list: []
+      list: [],
+      limit: 100,
     }
   },
-  mounted() { this.loadData(); },
+  mounted() {
+    this.events = subscribe({
+      terminated: (item) => {
+        this.list.unshift(item);
+        this.list.splice(this.limit);
+      },
+      lagged: () => this.loadData(true),
+    });
+    this.loadData(true);
+  },
+  unmounted() { this.events.close(); },
   methods: {
-    async loadData() {
+    // new entries come from the event stream, no need to poll
+    async loadData(force) {
+      if (!force) return;
       let response = await fetch(API_PREFIX + '/history')
       if (response.status !== 200) {
         console.log('Response Error', response);
@@ -145,6 +189,7 @@ app.component('tab-history', {
       let data = await response.json()
       // console.info(data);
       this.list = data;
+      this.limit = Math.max(this.limit, data.length);
     }
   }
 })